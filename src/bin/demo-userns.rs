@@ -1,5 +1,5 @@
-use caps;
 use clap::{crate_version, App, Arg};
+use namespaces_in_operation::stack::{self, ChildStack};
 use nix::sched::{clone, CloneFlags};
 use nix::sys::signal::Signal;
 use nix::sys::wait::waitpid;
@@ -8,8 +8,6 @@ use std::process;
 use std::thread;
 use std::time::Duration;
 
-const STACK_SIZE: usize = 1024 * 1024;
-
 fn child_func(do_loop: bool) -> isize {
     loop {
         println!("Child: eUID: {}, eGID: {}", geteuid(), getegid());
@@ -37,11 +35,13 @@ fn main() {
                 .short("l")
                 .long("loop"),
         )
+        .arg(stack::arg())
         .get_matches();
 
     let do_loop = matches.is_present("loop");
 
-    let mut child_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let stack_size = stack::from_matches(&matches);
+    let mut child_stack = ChildStack::new(stack_size);
 
     println!("Parent: eUID: {}, eGID: {}", geteuid(), getegid());
     println!(
@@ -51,7 +51,7 @@ fn main() {
 
     let pid = clone(
         Box::new(|| child_func(do_loop)),
-        &mut child_stack,
+        child_stack.as_mut_slice(),
         CloneFlags::CLONE_NEWUSER,
         Some(Signal::SIGCHLD as i32),
     )
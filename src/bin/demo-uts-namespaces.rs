@@ -1,16 +1,15 @@
+use clap::{crate_version, App, Arg};
+use namespaces_in_operation::stack::{self, ChildStack};
 use nix::sched::{clone, CloneFlags};
 use nix::sys::signal::Signal;
 use nix::sys::utsname::uname;
 use nix::sys::wait::waitpid;
 use nix::unistd::sethostname;
-use std::env;
 use std::process;
 use std::thread;
 use std::time::Duration;
 
-const STACK_LENGTH: usize = 1024 * 1024;
-
-fn child_func(hostname: &String) -> isize {
+fn child_func(hostname: &str) -> isize {
     // Change hostname in UTS namespace of child
     sethostname(hostname).expect("sethostname() failed");
 
@@ -26,19 +25,26 @@ fn child_func(hostname: &String) -> isize {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <child-hostname>", args[0]);
-        process::exit(1);
-    }
+    let matches = App::new("demo-uts-namespaces")
+        .version(crate_version!())
+        .arg(stack::arg())
+        .arg(
+            Arg::with_name("child-hostname")
+                .help("hostname to set in the child's UTS namespace")
+                .index(1)
+                .required(true),
+        )
+        .get_matches();
 
-    let mut child_stack: [u8; STACK_LENGTH] = [0; STACK_LENGTH];
+    let hostname = matches.value_of("child-hostname").unwrap();
+    let stack_size = stack::from_matches(&matches);
+    let mut child_stack = ChildStack::new(stack_size);
 
     // Create a child that has its own UTS namespace; the child commences
     // execution in `child_func` above.
     let pid = clone(
-        Box::new(|| child_func(&args[1])),
-        &mut child_stack,
+        Box::new(|| child_func(hostname)),
+        child_stack.as_mut_slice(),
         CloneFlags::CLONE_NEWUTS,
         Some(Signal::SIGCHLD as i32),
     )
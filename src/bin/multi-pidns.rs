@@ -1,4 +1,5 @@
 use clap::{crate_version, value_t, App, Arg};
+use namespaces_in_operation::stack::{self, ChildStack};
 use nix::mount::{mount, MsFlags};
 use nix::sched::{clone, CloneFlags};
 use nix::sys::signal::Signal;
@@ -9,9 +10,8 @@ use std::fs::create_dir_all;
 use std::process;
 
 const NONE: Option<&'static [u8]> = None;
-const STACK_SIZE: usize = 1024 * 1024;
 
-fn child_func(level: u8, first_call: bool) -> isize {
+fn child_func(level: u8, first_call: bool, stack_size: usize) -> isize {
     if !first_call {
         // Unless this is the first recursive call to  `child_func()`
         // (i.e., we were invoked from `main`), mount a procfs or the current
@@ -32,11 +32,12 @@ fn child_func(level: u8, first_call: bool) -> isize {
 
     if level > 0 {
         // Recursively invoke  `child_func()` to create another child in a
-        // nested PID namespace.
-        let mut child_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        // nested PID namespace. Each level gets a stack of its own, mapped
+        // apart from ours.
+        let mut child_stack = ChildStack::new(stack_size);
         let pid = clone(
-            Box::new(|| child_func(level - 1, false)),
-            &mut child_stack,
+            Box::new(|| child_func(level - 1, false, stack_size)),
+            child_stack.as_mut_slice(),
             CloneFlags::CLONE_NEWPID,
             Some(Signal::SIGCHLD as i32),
         )
@@ -52,7 +53,8 @@ fn child_func(level: u8, first_call: bool) -> isize {
             CString::new("1000").unwrap(),
         ];
         let args_exec: Vec<&CStr> = args_owned.iter().map(CString::as_c_str).collect();
-        execvp(&args_exec[0], &args_exec).expect("execvp() failed");
+        let Err(err) = execvp(args_exec[0], &args_exec);
+        panic!("execvp() failed: {:?}", err);
     }
 
     0
@@ -68,9 +70,11 @@ fn main() {
                 .long("levels")
                 .short("l"),
         )
+        .arg(stack::arg())
         .get_matches();
 
     let levels = value_t!(matches, "levels", u8).unwrap();
-    child_func(levels, true);
+    let stack_size = stack::from_matches(&matches);
+    child_func(levels, true, stack_size);
     process::exit(0);
 }
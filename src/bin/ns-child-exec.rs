@@ -1,17 +1,63 @@
 use clap::{crate_version, App, Arg};
-use nix::sched::{clone, CloneFlags};
-use nix::sys::signal::Signal;
-use nix::sys::wait::waitpid;
-use nix::unistd::execvp;
+use namespaces_in_operation::cgroup;
+use namespaces_in_operation::errpipe::{ErrorPipe, Step};
+use namespaces_in_operation::forward::{self, Forwarder};
+use namespaces_in_operation::mountns::{set_propagation, MountSpec, Propagation};
+use namespaces_in_operation::spawn::spawn;
+use namespaces_in_operation::stack::{self, ChildStack};
+use namespaces_in_operation::status::{describe, exit_code};
+use namespaces_in_operation::timens::{self, enter_new_time};
+use namespaces_in_operation::utsns::{self, set_uts_names};
+use nix::fcntl::OFlag;
+use nix::sched::CloneFlags;
+use nix::unistd::{close, execvp, pipe2, read};
 use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::process;
 
-fn child_func(args: &[&CStr]) -> isize {
-    execvp(&args[0], &args).expect("exec() failed");
-    0
+// What the child sets up inside its new namespaces before executing its
+// command.
+struct Setup<'a> {
+    args: Vec<&'a CStr>,
+    propagation: Propagation,
+    mounts: Vec<MountSpec>,
+    hostname: Option<&'a str>,
+    domainname: Option<&'a str>,
+    // Pipe on which to wait until the parent has moved us into our cgroup.
+    sync: Option<(RawFd, RawFd)>,
+    // Offsets of CLOCK_MONOTONIC and CLOCK_BOOTTIME, if we are to enter a
+    // new time namespace.
+    time: Option<(Option<i64>, Option<i64>)>,
 }
 
-const STACK_LENGTH: usize = 1024 * 1024;
+fn child_func(setup: &Setup, errors: &ErrorPipe, forwarder: &Forwarder) -> isize {
+    if let Some((reader, writer)) = setup.sync {
+        errors.check(Step::Sync, close(writer));
+        let mut buf: [u8; 1] = [0; 1];
+        errors.check(Step::Sync, read(reader, &mut buf));
+        errors.check(Step::Sync, close(reader));
+    }
+
+    // Time namespaces cannot be requested through `clone()`, so we create
+    // one now and move into it.
+    if let Some((monotonic, boottime)) = setup.time {
+        errors.check(Step::Time, enter_new_time(monotonic, boottime));
+    }
+
+    errors.check(Step::Mount, set_propagation(setup.propagation));
+    for spec in &setup.mounts {
+        errors.check(Step::Mount, spec.apply(""));
+    }
+    errors.check(
+        Step::Hostname,
+        set_uts_names(setup.hostname, setup.domainname),
+    );
+    errors.check(Step::SignalMask, forwarder.restore());
+
+    let Err(err) = execvp(setup.args[0], &setup.args);
+    errors.fail(Step::Exec, err)
+}
 
 fn main() {
     let matches = App::new("ns-child-exec")
@@ -28,6 +74,8 @@ fn main() {
                 .short("m")
                 .long("mount"),
         )
+        .arg(Propagation::arg())
+        .args(&MountSpec::args())
         .arg(
             Arg::with_name("net")
                 .help("unshare network namespace")
@@ -46,12 +94,29 @@ fn main() {
                 .short("u")
                 .long("uts"),
         )
+        .args(&utsns::args())
         .arg(
             Arg::with_name("user")
                 .help("unshare user namespace")
                 .short("U")
                 .long("user"),
         )
+        .arg(
+            Arg::with_name("cgroup")
+                .help("unshare cgroup namespace")
+                .short("C")
+                .long("cgroup"),
+        )
+        .arg(
+            Arg::with_name("time")
+                .help("unshare time namespace")
+                .short("T")
+                .long("time"),
+        )
+        .args(&timens::args())
+        .args(&cgroup::args())
+        .args(&forward::args())
+        .arg(stack::arg())
         .arg(
             Arg::with_name("verbose")
                 .help("verbose operation")
@@ -62,11 +127,13 @@ fn main() {
         .arg(Arg::with_name("arg").multiple(true))
         .get_matches();
 
+    let mounts = MountSpec::from_matches(&matches);
+
     let mut flags = CloneFlags::empty();
     if matches.is_present("ipc") {
         flags.set(CloneFlags::CLONE_NEWIPC, true)
     }
-    if matches.is_present("mount") {
+    if matches.is_present("mount") || !mounts.is_empty() {
         flags.set(CloneFlags::CLONE_NEWNS, true)
     }
     if matches.is_present("net") {
@@ -75,16 +142,35 @@ fn main() {
     if matches.is_present("pid") {
         flags.set(CloneFlags::CLONE_NEWPID, true)
     }
-    if matches.is_present("uts") {
+    let (hostname, domainname) = utsns::from_matches(&matches);
+    if matches.is_present("uts") || hostname.is_some() || domainname.is_some() {
         flags.set(CloneFlags::CLONE_NEWUTS, true)
     }
     if matches.is_present("user") {
         flags.set(CloneFlags::CLONE_NEWUSER, true)
     }
+    if matches.is_present("cgroup") {
+        flags.set(CloneFlags::CLONE_NEWCGROUP, true)
+    }
+
+    let (monotonic, boottime) = timens::from_matches(&matches);
+    let time = matches.is_present("time") || monotonic.is_some() || boottime.is_some();
+
+    let propagation = if flags.contains(CloneFlags::CLONE_NEWNS) {
+        Propagation::from_matches(&matches)
+    } else {
+        Propagation::Unchanged
+    };
+
+    // Signals are passed on to the child, except that with `--kill-signal`,
+    // those asking us to terminate are replaced by the given one, followed
+    // by SIGKILL if need be.
+    let kill = forward::from_matches(&matches);
 
     let verbose = matches.is_present("verbose");
 
-    let mut child_stack: [u8; STACK_LENGTH] = [0; STACK_LENGTH];
+    let stack_size = stack::from_matches(&matches);
+    let mut child_stack = ChildStack::new(stack_size);
 
     let cmd = matches.value_of("cmd").unwrap();
     let mut args_exec_owned: Vec<CString> = vec![CString::new(cmd).unwrap()];
@@ -94,25 +180,110 @@ fn main() {
             .unwrap()
             .for_each(|a| args_exec_owned.push(CString::new(a).unwrap()));
     }
-    let args_exec: Vec<&CStr> = args_exec_owned.iter().map(CString::as_c_str).collect();
 
-    let pid = clone(
-        Box::new(|| child_func(&args_exec)),
-        &mut child_stack,
+    // The cgroup is created before the child. Unless the child is created
+    // in it, it waits on a pipe until we have moved it into the cgroup, so
+    // that it may not execute its command before.
+    let cgroup = cgroup::from_matches(&matches).unwrap_or_else(|err| {
+        eprintln!("ns-child-exec: {}", err);
+        process::exit(1);
+    });
+    let setup = Setup {
+        args: args_exec_owned.iter().map(CString::as_c_str).collect(),
+        propagation,
+        mounts,
+        hostname,
+        domainname,
+        sync: cgroup
+            .as_ref()
+            .map(|_| pipe2(OFlag::O_CLOEXEC).expect("pipe2() failed")),
+        time: if time {
+            Some((monotonic, boottime))
+        } else {
+            None
+        },
+    };
+
+    // Any failure of the child before it executes its command is reported
+    // back to us through this pipe.
+    let errors = ErrorPipe::new();
+
+    // Before the child and any other threads are created; see `Forwarder`.
+    let mut forwarder = Forwarder::new(kill).expect("Error blocking signals");
+
+    // With `clone3()`, the child is created in its cgroup right away.
+    let cgroup_dir = cgroup.as_ref().map(|cgroup| {
+        File::open(cgroup.path()).map_err(|err| format!("{}: {}", cgroup.path().display(), err))
+    });
+    let cgroup_dir = match cgroup_dir.transpose() {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("ns-child-exec: {}", err);
+            cgroup::discard(cgroup);
+        }
+    };
+    let child = match spawn(
+        || child_func(&setup, &errors, &forwarder),
+        child_stack.as_mut_slice(),
         flags,
-        Some(Signal::SIGCHLD as i32),
-    )
-    .expect("clone() failed");
+        cgroup_dir.as_ref().map(File::as_raw_fd),
+    ) {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("ns-child-exec: clone() failed: {}", err);
+            cgroup::discard(cgroup);
+        }
+    };
+    drop(cgroup_dir);
+    if let Some((reader, _)) = setup.sync {
+        close(reader).expect("close() failed");
+    }
+    let pid = child.pid;
 
     if verbose {
-        println!("ns-child-exec: PID of child created by clone is {}", pid);
+        println!(
+            "ns-child-exec: PID of child created by {} is {}",
+            child.created_by(),
+            pid
+        );
+    }
+
+    if let Some(Err(err)) = cgroup
+        .as_ref()
+        .filter(|_| !child.in_cgroup)
+        .map(|cgroup| cgroup.add(pid))
+    {
+        eprintln!("ns-child-exec: {}", err);
+        child.abort(cgroup);
+    }
+    if let Some((_, writer)) = setup.sync {
+        close(writer).expect("close() failed");
     }
 
-    // Parent process: Wait for child.
-    waitpid(pid, None).expect("waitpid() failed");
+    let failure = errors.wait();
+
+    // Parent process: Wait for child, and pass its exit status on.
+    let status = forwarder.wait(&child).expect("waitpid() failed");
+
+    if let Some(cgroup) = cgroup {
+        if verbose {
+            for usage in cgroup.usage() {
+                println!("ns-child-exec: {}", usage);
+            }
+        }
+        if let Err(err) = cgroup.remove() {
+            eprintln!("ns-child-exec: {}", err);
+        }
+    }
+
+    if let Some(failure) = failure {
+        eprintln!("ns-child-exec: {}", failure);
+        process::exit(failure.exit_code());
+    }
 
     if verbose {
+        println!("ns-child-exec: Child {}", describe(status));
         println!("ns-child-exec: Terminating");
     }
-    process::exit(0);
+    process::exit(exit_code(status));
 }
@@ -1,37 +1,150 @@
+use clap::{crate_version, value_t, App, AppSettings, Arg};
+use namespaces_in_operation::ns::{check_ns_file, NsType};
+use namespaces_in_operation::pidfd::{pidfd_open, setns_pidfd};
+use namespaces_in_operation::status::exit_code;
+use nix::errno::Errno;
 use nix::sched::{setns, CloneFlags};
-use nix::unistd::execvp;
-use std::env;
+use nix::sys::wait::waitpid;
+use nix::unistd::{execvp, fork, ForkResult, Pid};
+use nix::Error;
 use std::ffi::{CStr, CString};
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 use std::process;
 
+// Split a path of the form `/proc/PID/ns/NAME` into its PID and namespace
+// type.
+fn parse_proc_ns_path(path: &str) -> Option<(Pid, NsType)> {
+    let mut parts = path.strip_prefix("/proc/")?.split('/');
+    let pid = parts.next()?.parse().ok()?;
+    if parts.next()? != "ns" {
+        return None;
+    }
+    let nstype = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((Pid::from_raw(pid), nstype))
+}
+
+// Join the namespace behind `/proc/PID/ns/NAME` through a pidfd for PID.
+// Returns false if the kernel does not support that.
+fn join_through_pidfd(pid: Pid, nstype: NsType) -> bool {
+    let pidfd = match pidfd_open(pid) {
+        Ok(pidfd) => pidfd,
+        Err(Error::Sys(Errno::ENOSYS)) => return false,
+        Err(err) => panic!("pidfd_open() failed: {}", err),
+    };
+    match setns_pidfd(&pidfd, &[nstype]) {
+        Ok(()) => true,
+        Err(Error::Sys(Errno::EINVAL)) => false,
+        Err(err) => panic!("setns() failed: {}", err),
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!(
-            "Usage: {} /proc/<pid>/ns/<ns-file> <cmd> [<arg> ..]",
-            args[0]
-        );
-        process::exit(1);
+    let matches = App::new("ns-exec")
+        .version(crate_version!())
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("pidfd")
+                .help("join through a pidfd for PID, given a /proc/PID/ns/<ns> path")
+                .long("pidfd"),
+        )
+        .arg(
+            Arg::with_name("type")
+                .help("refuse to join a namespace of any other type")
+                .short("t")
+                .long("type")
+                .takes_value(true)
+                .possible_values(NsType::VALUES)
+                .value_name("TYPE"),
+        )
+        .arg(
+            Arg::with_name("ns-file")
+                .help("namespace file to join, such as /proc/PID/ns/<ns>")
+                .index(1)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("cmd")
+                .help("command to run, followed by its arguments")
+                .index(2)
+                .multiple(true)
+                .required(true),
+        )
+        .get_matches();
+
+    let ns_file = matches.value_of("ns-file").unwrap();
+    let expected = matches
+        .value_of("type")
+        .map(|_| value_t!(matches, "type", NsType).unwrap());
+
+    // In `--pidfd` mode, the namespace type comes from the path.
+    let mut joined = None;
+    if matches.is_present("pidfd") {
+        let (pid, nstype) = parse_proc_ns_path(ns_file).unwrap_or_else(|| {
+            clap::Error::with_description(
+                "--pidfd requires a /proc/PID/ns/<ns> path",
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+        if let Some(expected) = expected.filter(|&t| t != nstype) {
+            eprintln!(
+                "ns-exec: {}: is a {} namespace, not a {} namespace",
+                ns_file, nstype, expected
+            );
+            process::exit(1);
+        }
+        if join_through_pidfd(pid, nstype) {
+            joined = Some(nstype);
+        }
     }
 
-    // Get descriptor for namespace.
-    let fd = OpenOptions::new()
-        .read(true)
-        .open(&args[1])
-        .expect("open() failed");
+    let joined = joined.unwrap_or_else(|| {
+        // Get descriptor for namespace.
+        let fd = OpenOptions::new()
+            .read(true)
+            .open(ns_file)
+            .expect("open() failed");
 
-    // Join that namespace.
-    setns(fd.as_raw_fd(), CloneFlags::empty()).expect("setns() failed");
+        // Refuse anything but a namespace of the expected type, rather than
+        // leaving it to `setns()` to fail with a bare EINVAL.
+        let nstype = check_ns_file(&fd, ns_file, expected).unwrap_or_else(|err| {
+            eprintln!("ns-exec: {}", err);
+            process::exit(1);
+        });
+
+        // Join that namespace.
+        let flags = expected.map_or(CloneFlags::empty(), NsType::clone_flags);
+        setns(fd.as_raw_fd(), flags).expect("setns() failed");
+        nstype
+    });
+
+    // Joining a PID or time namespace only affects our future children, so
+    // the command has to run in a child of ours.
+    if joined == NsType::Pid || joined == NsType::Time {
+        match fork().expect("fork() failed") {
+            ForkResult::Parent { child } => {
+                // Wait for child and pass its exit status on.
+                let status = waitpid(child, None).expect("waitpid() failed");
+                process::exit(exit_code(status));
+            }
+            ForkResult::Child => {
+                // Fall through to code below.
+            }
+        }
+    }
 
     // Execute a command in namespace
-    let args_exec_owned: Vec<CString> = args
-        .iter()
-        .skip(2)
-        .map(|a| CString::new(a.as_bytes()).unwrap())
+    let args_exec_owned: Vec<CString> = matches
+        .values_of("cmd")
+        .unwrap()
+        .map(|a| CString::new(a).unwrap())
         .collect();
     let args_exec: Vec<&CStr> = args_exec_owned.iter().map(CString::as_c_str).collect();
 
-    execvp(&args_exec[0], &args_exec).expect("exec() failed");
+    let Err(err) = execvp(args_exec[0], &args_exec);
+    panic!("exec() failed: {:?}", err);
 }
@@ -0,0 +1,26 @@
+use clap::{crate_version, App, Arg};
+use namespaces_in_operation::persist::release;
+use std::process;
+
+fn main() {
+    let matches = App::new("ns-release")
+        .version(crate_version!())
+        .about("Release namespaces persisted with `unshare --<ns>=FILE`")
+        .arg(
+            Arg::with_name("file")
+                .help("file a namespace is bind-mounted onto")
+                .multiple(true)
+                .required(true),
+        )
+        .get_matches();
+
+    let mut failed = false;
+    for path in matches.values_of("file").unwrap() {
+        if let Err(err) = release(path) {
+            eprintln!("ns-release: {}: {}", path, err);
+            failed = true;
+        }
+    }
+
+    process::exit(if failed { 1 } else { 0 });
+}
@@ -1,12 +1,72 @@
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, value_t, App, Arg, ArgGroup};
+use namespaces_in_operation::ns::{check_ns_file, NsType};
+use namespaces_in_operation::pidfd::{pidfd_open, setns_pidfd};
+use namespaces_in_operation::status::exit_code;
+use nix::errno::Errno;
 use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::{fstat, stat};
 use nix::sys::wait::waitpid;
-use nix::unistd::{execvp, fork, ForkResult};
+use nix::unistd::{chroot, execvp, fchdir, fork, geteuid, ForkResult, Pid};
+use nix::Error;
 use std::ffi::{CStr, CString};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::os::unix::io::AsRawFd;
 use std::process;
 
+// Per-type options for `--target`, as pairs of argument name and
+// namespace type.
+const TARGET_NAMESPACES: &[(&str, NsType)] = &[
+    ("mount", NsType::Mnt),
+    ("uts", NsType::Uts),
+    ("ipc", NsType::Ipc),
+    ("net", NsType::Net),
+    ("pid", NsType::Pid),
+    ("user", NsType::User),
+    ("cgroup", NsType::Cgroup),
+    ("time", NsType::Time),
+];
+
+fn open_ns(path: &str) -> File {
+    OpenOptions::new()
+        .read(true)
+        .open(path)
+        .unwrap_or_else(|err| panic!("open({}) failed: {}", path, err))
+}
+
+// Whether `fd` refers to the namespace of type `ns` we are already a
+// member of.
+fn is_current_ns(fd: &File, ns: NsType) -> bool {
+    let target = fstat(fd.as_raw_fd()).expect("fstat() failed");
+    let ours = stat(format!("/proc/self/ns/{}", ns).as_str()).expect("stat() failed");
+    (target.st_dev, target.st_ino) == (ours.st_dev, ours.st_ino)
+}
+
+// Order in which to join the namespaces of a target process. Joining a user
+// namespace grants us capabilities inside it, but drops those we have in
+// our current user namespace. An unprivileged caller thus needs to join the
+// target's user namespace first, while a privileged caller may join it
+// after the others. The mount namespace comes last, since joining it
+// changes our root and working directories.
+fn join_order(privileged: bool) -> Vec<NsType> {
+    let mut order = Vec::new();
+    if !privileged {
+        order.push(NsType::User);
+    }
+    order.extend_from_slice(&[
+        NsType::Cgroup,
+        NsType::Ipc,
+        NsType::Uts,
+        NsType::Net,
+        NsType::Pid,
+        NsType::Time,
+    ]);
+    if privileged {
+        order.push(NsType::User);
+    }
+    order.push(NsType::Mnt);
+    order
+}
+
 fn main() {
     let matches = App::new("ns-run")
         .version(crate_version!())
@@ -19,33 +79,243 @@ fn main() {
         .arg(
             Arg::with_name("ns")
                 .help("path to the /proc/PID/ns/<ns> of the namespace to join")
-                .short("n")
                 .long("ns")
-                .required(true)
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required_unless("target")
                 .value_name("PATH"),
         )
+        .arg(
+            Arg::with_name("type")
+                .help("refuse to join --ns namespaces of any other type")
+                .long("type")
+                .takes_value(true)
+                .possible_values(NsType::VALUES)
+                .requires("ns")
+                .value_name("TYPE"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .help("join the namespaces of process PID")
+                .short("t")
+                .long("target")
+                .takes_value(true)
+                .value_name("PID")
+                .conflicts_with("ns")
+                .requires("target-namespaces"),
+        )
+        .arg(
+            Arg::with_name("mount")
+                .help("join the mount namespace of the target")
+                .short("m")
+                .long("mount")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("uts")
+                .help("join the UTS namespace of the target")
+                .short("u")
+                .long("uts")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("ipc")
+                .help("join the IPC namespace of the target")
+                .short("i")
+                .long("ipc")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("net")
+                .help("join the network namespace of the target")
+                .short("n")
+                .long("net")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("pid")
+                .help("join the PID namespace of the target")
+                .short("p")
+                .long("pid")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .help("join the user namespace of the target")
+                .short("U")
+                .long("user")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("cgroup")
+                .help("join the cgroup namespace of the target")
+                .short("C")
+                .long("cgroup")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("time")
+                .help("join the time namespace of the target")
+                .short("T")
+                .long("time")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("all")
+                .help("join all namespaces of the target")
+                .short("a")
+                .long("all")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("pidfd")
+                .help("join the namespaces of the target atomically, through a pidfd")
+                .long("pidfd")
+                .requires("target"),
+        )
+        .arg(
+            Arg::with_name("root")
+                .help("set the root directory to DIR (default: that of the target)")
+                .long("root")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("wd")
+                .help("set the working directory to DIR (default: that of the target)")
+                .long("wd")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("DIR"),
+        )
+        .group(
+            ArgGroup::with_name("target-namespaces")
+                .args(&[
+                    "mount", "uts", "ipc", "net", "pid", "user", "cgroup", "time", "all",
+                ])
+                .multiple(true),
+        )
         .arg(Arg::with_name("cmd").index(1).required(true))
         .arg(Arg::with_name("arg").multiple(true))
         .get_matches();
 
     let do_fork = matches.is_present("fork");
 
-    // Get descriptor for namespace.
-    let fd = OpenOptions::new()
-        .read(true)
-        .open(&matches.value_of("ns").unwrap())
-        .expect("open() failed");
+    // In `--pidfd` mode we refer to the target through a pidfd, which
+    // cannot be recycled to refer to another process, as PIDs can. The
+    // kernel may be too old for that, though.
+    let pidfd = if matches.is_present("pidfd") {
+        let target = value_t!(matches, "target", i32).unwrap_or_else(|e| e.exit());
+        match pidfd_open(Pid::from_raw(target)) {
+            Ok(pidfd) => Some(pidfd),
+            Err(Error::Sys(Errno::ENOSYS)) => None,
+            Err(err) => panic!("pidfd_open() failed: {}", err),
+        }
+    } else {
+        None
+    };
+
+    // Get descriptors for the new root and working directories, to be
+    // applied after joining the namespaces (a path would be resolved in
+    // the wrong mount namespace by then). Unless given, they are those of
+    // the target.
+    let dir = |arg: &str, proc_entry: &str| {
+        if !matches.is_present(arg) {
+            return None;
+        }
+        let path = match (matches.value_of(arg), matches.value_of("target")) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(target)) => format!("/proc/{}/{}", target, proc_entry),
+            (None, None) => clap::Error::with_description(
+                &format!("--{} requires a DIR unless --target is given", arg),
+                clap::ErrorKind::EmptyValue,
+            )
+            .exit(),
+        };
+        Some(File::open(&path).unwrap_or_else(|err| panic!("open({}) failed: {}", path, err)))
+    };
+    let root = dir("root", "root");
+    let wd = dir("wd", "cwd");
+
+    // Get descriptors for all namespaces before joining any of them, since
+    // the target's `/proc/PID/ns` may no longer be reachable afterwards.
+    let mut namespaces: Vec<(File, Option<NsType>)> = Vec::new();
+    if matches.is_present("ns") {
+        let expected = matches
+            .value_of("type")
+            .map(|_| value_t!(matches, "type", NsType).unwrap());
+        for path in matches.values_of("ns").unwrap() {
+            let fd = open_ns(path);
 
-    // Join that namespace.
-    setns(fd.as_raw_fd(), CloneFlags::empty()).expect("setns() failed");
+            // Refuse anything but a namespace of the expected type, rather
+            // than leaving it to `setns()` to fail with a bare EINVAL.
+            if let Err(err) = check_ns_file(&fd, path, expected) {
+                eprintln!("ns-run: {}", err);
+                process::exit(1);
+            }
+            namespaces.push((fd, expected));
+        }
+    } else {
+        let target = value_t!(matches, "target", u32).unwrap_or_else(|e| e.exit());
+        let all = matches.is_present("all");
+        for ns in join_order(geteuid().is_root()) {
+            let selected = TARGET_NAMESPACES
+                .iter()
+                .any(|&(arg, t)| t == ns && (all || matches.is_present(arg)));
+            if !selected {
+                continue;
+            }
+
+            let fd = open_ns(&format!("/proc/{}/ns/{}", target, ns));
+
+            // Joining the user namespace we are already in fails with
+            // EINVAL, and the others would be a no-op.
+            if is_current_ns(&fd, ns) {
+                continue;
+            }
+            namespaces.push((fd, Some(ns)));
+        }
+    }
+
+    // Join those namespaces, all at once through the pidfd if we can, or
+    // one at a time otherwise.
+    let joined = match pidfd {
+        Some(pidfd) => {
+            let nstypes: Vec<NsType> = namespaces.iter().filter_map(|&(_, t)| t).collect();
+            match setns_pidfd(&pidfd, &nstypes) {
+                Ok(()) => true,
+                Err(Error::Sys(Errno::EINVAL)) => false,
+                Err(err) => panic!("setns() failed: {}", err),
+            }
+        }
+        None => false,
+    };
+    if !joined {
+        for (fd, nstype) in &namespaces {
+            let nstype = nstype.map_or(CloneFlags::empty(), NsType::clone_flags);
+            setns(fd.as_raw_fd(), nstype).expect("setns() failed");
+        }
+    }
+    drop(namespaces);
+
+    if let Some(root) = root {
+        fchdir(root.as_raw_fd()).expect("fchdir() failed");
+        chroot(".").expect("chroot() failed");
+    }
+    if let Some(wd) = wd {
+        fchdir(wd.as_raw_fd()).expect("fchdir() failed");
+    }
 
     if do_fork {
         match fork().expect("fork() failed") {
             ForkResult::Parent { child } => {
-                // Wait for child and exit.
-                waitpid(child, None).expect("waitpid() failed");
-                process::exit(0);
+                // Wait for child and pass its exit status on.
+                let status = waitpid(child, None).expect("waitpid() failed");
+                process::exit(exit_code(status));
             }
             ForkResult::Child => {
                 // Fall through to code below.
@@ -62,5 +332,6 @@ fn main() {
             .for_each(|a| args_owned.push(CString::new(a).unwrap()));
     }
     let args_exec: Vec<&CStr> = args_owned.iter().map(CString::as_c_str).collect();
-    execvp(&args_exec[0], &args_exec).expect("execvp() failed");
+    let Err(err) = execvp(args_exec[0], &args_exec);
+    panic!("execvp() failed: {:?}", err);
 }
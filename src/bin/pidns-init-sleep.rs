@@ -1,9 +1,10 @@
+use clap::{crate_version, App, Arg};
+use namespaces_in_operation::stack::{self, ChildStack};
 use nix::mount::{mount, umount, MsFlags};
 use nix::sched::{clone, CloneFlags};
 use nix::sys::signal::Signal;
 use nix::sys::wait::waitpid;
 use nix::unistd::execvp;
-use std::env;
 use std::ffi::{CStr, CString};
 use std::fs::{create_dir_all, remove_dir};
 use std::os::unix::process::parent_id;
@@ -31,25 +32,28 @@ fn child_func(mount_point: &str) -> isize {
         CString::new("1000").unwrap(),
     ];
     let args_exec: Vec<&CStr> = args_owned.iter().map(CString::as_c_str).collect();
-    execvp(&args_exec[0], &args_exec).expect("execvp() failed");
-
-    0
+    let Err(err) = execvp(args_exec[0], &args_exec);
+    panic!("execvp() failed: {:?}", err)
 }
 
-const STACK_SIZE: usize = 1024 * 1024;
-
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <proc-mount-point>", args[0]);
-        process::exit(1);
-    }
-    let mount_point = &args[1];
+    let matches = App::new("pidns-init-sleep")
+        .version(crate_version!())
+        .arg(stack::arg())
+        .arg(
+            Arg::with_name("proc-mount-point")
+                .help("where to mount the procfs of the new PID namespace")
+                .index(1)
+                .required(true),
+        )
+        .get_matches();
 
-    let mut child_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let mount_point = matches.value_of("proc-mount-point").unwrap();
+    let stack_size = stack::from_matches(&matches);
+    let mut child_stack = ChildStack::new(stack_size);
     let pid = clone(
         Box::new(|| child_func(mount_point)),
-        &mut child_stack,
+        child_stack.as_mut_slice(),
         CloneFlags::CLONE_NEWPID,
         Some(Signal::SIGCHLD as i32),
     )
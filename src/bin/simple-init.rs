@@ -139,7 +139,8 @@ fn main() {
                         // Child executes shell command and terminates.
                         let args_exec: Vec<&CStr> =
                             args_owned.iter().map(CString::as_c_str).collect();
-                        execvp(&args_exec[0], &args_exec).expect("execvp() failed");
+                        let Err(err) = execvp(args_exec[0], &args_exec);
+                        panic!("execvp() failed: {:?}", err);
                     }
                     ForkResult::Parent { child } => {
                         if verbose {
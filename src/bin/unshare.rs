@@ -1,73 +1,241 @@
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, ArgGroup};
+use namespaces_in_operation::mountns::{set_propagation, Propagation};
+use namespaces_in_operation::persist::Persister;
+use namespaces_in_operation::status::exit_code;
+use namespaces_in_operation::timens::{self, unshare_time};
+use namespaces_in_operation::userns::{disable_setgroups, update_map, IdMap};
+use nix::mount::{mount, MsFlags};
 use nix::sched::{unshare, CloneFlags};
-use nix::unistd::execvp;
+use nix::sys::wait::waitpid;
+use nix::unistd::{execvp, fork, getegid, geteuid, ForkResult};
 use std::ffi::{CStr, CString};
+use std::process;
 
-fn main() {
-    let matches = App::new("unshare")
-        .version(crate_version!())
-        .arg(
-            Arg::with_name("ipc")
-                .help("unshare IPC namespace")
-                .short("i")
-                .long("ipc"),
-        )
-        .arg(
-            Arg::with_name("mount")
-                .help("unshare mount namespace")
-                .short("m")
-                .long("mount"),
+const NONE: Option<&'static [u8]> = None;
+
+// Mount a fresh procfs at `mount_point`. Unless the mounts of the new mount
+// namespace were made private already, the mount point is made private
+// first, as util-linux does, lest the new procfs propagate to a peer mount
+// of the parent mount namespace and hide its `/proc`.
+fn mount_proc(mount_point: &str, propagation: Propagation) {
+    if propagation != Propagation::Private {
+        mount(
+            NONE,
+            mount_point,
+            NONE,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            NONE,
         )
+        .unwrap_or_else(|err| panic!("Error making {} private: {}", mount_point, err));
+    }
+    mount(
+        Some("proc"),
+        mount_point,
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        NONE,
+    )
+    .expect("mount() of procfs failed");
+}
+
+// Namespace options, as tuples of argument name, short option, help text and
+// the entry in `/proc/PID/ns` used to persist the namespace. Our PID and time
+// namespaces are only entered by our children, hence the `*_for_children`
+// entries.
+//
+// The short options are plain flags, so that they can still be combined (as
+// in `-Ufp`), whereas the long ones take an optional FILE to bind-mount the
+// new namespace onto.
+const NAMESPACES: &[(&str, &str, &str, &str)] = &[
+    ("ipc", "i", "unshare IPC namespace", "ipc"),
+    ("mount", "m", "unshare mount namespace", "mnt"),
+    ("net", "n", "unshare network namespace", "net"),
+    ("pid", "p", "unshare PID namespace", "pid_for_children"),
+    ("uts", "u", "unshare UTS namespace", "uts"),
+    ("user", "U", "unshare user namespace", "user"),
+    ("cgroup", "C", "unshare cgroup namespace", "cgroup"),
+    ("time", "T", "unshare time namespace", "time_for_children"),
+];
+
+fn main() {
+    let mut app = App::new("unshare").version(crate_version!()).after_help(
+        "A namespace requested with --<ns>=FILE is persisted by bind-mounting it onto \
+             FILE. Use ns-release to release it.",
+    );
+    for &(name, short, help, _) in NAMESPACES {
+        app = app.arg(Arg::with_name(short).help(help).short(short)).arg(
+            Arg::with_name(name)
+                .help(help)
+                .long(name)
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("FILE"),
+        );
+    }
+
+    let matches = app
+        .arg(Propagation::arg())
+        .args(&timens::args())
         .arg(
-            Arg::with_name("net")
-                .help("unshare network namespace")
-                .short("n")
-                .long("net"),
+            Arg::with_name("fork")
+                .help("fork before exec, so that the command runs inside a new PID namespace")
+                .short("f")
+                .long("fork"),
         )
         .arg(
-            Arg::with_name("pid")
-                .help("unshare PID namespace")
-                .short("p")
-                .long("pid"),
+            Arg::with_name("mount-proc")
+                .help("mount procfs at DIR (default /proc); implies --mount")
+                .long("mount-proc")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("DIR"),
         )
         .arg(
-            Arg::with_name("uts")
-                .help("unshare UTS namespace")
-                .short("u")
-                .long("uts"),
+            Arg::with_name("map-root-user")
+                .help("map current user and group to root in the new user namespace; implies --user")
+                .short("r")
+                .long("map-root-user"),
         )
         .arg(
-            Arg::with_name("user")
-                .help("unshare user namespace")
-                .short("U")
-                .long("user"),
+            Arg::with_name("map-current-user")
+                .help("map current user and group to themselves in the new user namespace; implies --user")
+                .short("c")
+                .long("map-current-user"),
         )
+        .group(ArgGroup::with_name("map-user").args(&["map-root-user", "map-current-user"]))
         .arg(Arg::with_name("cmd").index(1).required(true))
         .arg(Arg::with_name("arg").multiple(true))
         .get_matches();
 
+    let requested = |name: &str| {
+        NAMESPACES.iter().any(|&(n, short, _, _)| {
+            n == name && (matches.is_present(n) || matches.is_present(short))
+        })
+    };
+
     let mut flags = CloneFlags::empty();
-    if matches.is_present("ipc") {
+    if requested("ipc") {
         flags.set(CloneFlags::CLONE_NEWIPC, true)
     }
-    if matches.is_present("mount") {
+    if requested("mount") || matches.is_present("mount-proc") {
         flags.set(CloneFlags::CLONE_NEWNS, true)
     }
-    if matches.is_present("net") {
+    if requested("net") {
         flags.set(CloneFlags::CLONE_NEWNET, true)
     }
-    if matches.is_present("pid") {
+    if requested("pid") {
         flags.set(CloneFlags::CLONE_NEWPID, true)
     }
-    if matches.is_present("uts") {
+    if requested("uts") {
         flags.set(CloneFlags::CLONE_NEWUTS, true)
     }
-    if matches.is_present("user") {
+    if requested("user") || matches.is_present("map-user") {
         flags.set(CloneFlags::CLONE_NEWUSER, true)
     }
+    if requested("cgroup") {
+        flags.set(CloneFlags::CLONE_NEWCGROUP, true)
+    }
+
+    let (monotonic, boottime) = timens::from_matches(&matches);
+    let time = requested("time") || monotonic.is_some() || boottime.is_some();
+
+    // Namespaces to be bind-mounted onto files, as pairs of `/proc/PID/ns`
+    // entries and paths.
+    let persist: Vec<(&str, &str)> = NAMESPACES
+        .iter()
+        .filter_map(|&(name, _, _, ns)| matches.value_of(name).map(|path| (ns, path)))
+        .collect();
+    if matches.value_of("pid").is_some() && !matches.is_present("fork") {
+        clap::Error::with_description(
+            "--pid=FILE requires --fork: a PID namespace can only be persisted once it has a process",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let persister = if persist.is_empty() {
+        None
+    } else {
+        Some(Persister::spawn(&persist))
+    };
+
+    // Our credentials have to be obtained before unshare(CLONE_NEWUSER),
+    // since afterwards they read as the overflow IDs until the maps are
+    // written.
+    let (euid, egid) = (geteuid(), getegid());
 
     unshare(flags).expect("unshare() failed");
 
+    if flags.contains(CloneFlags::CLONE_NEWNS) {
+        let propagation = Propagation::from_matches(&matches);
+        set_propagation(propagation)
+            .unwrap_or_else(|err| panic!("Error making mounts {}: {}", propagation, err));
+    }
+
+    if matches.is_present("map-user") {
+        let (uid_inside, gid_inside) = if matches.is_present("map-root-user") {
+            (0, 0)
+        } else {
+            (euid.as_raw(), egid.as_raw())
+        };
+
+        // Disable system call `setgroups(2)`, otherwise writing to the
+        // `gid_map` will fail.
+        disable_setgroups("/proc/self/setgroups")
+            .and_then(|()| {
+                update_map(
+                    &IdMap::single(uid_inside, euid.as_raw()),
+                    "/proc/self/uid_map",
+                )
+            })
+            .and_then(|()| {
+                update_map(
+                    &IdMap::single(gid_inside, egid.as_raw()),
+                    "/proc/self/gid_map",
+                )
+            })
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    // The time namespace is created after the user namespace, so that it is
+    // owned by the latter and we may set its clock offsets.
+    if time {
+        unshare_time(monotonic, boottime)
+            .unwrap_or_else(|err| panic!("Error creating time namespace: {}", err));
+    }
+
+    // After unshare(CLONE_NEWPID) only our children become members of the
+    // new PID namespace, so we need to fork for the command to run inside
+    // it.
+    if matches.is_present("fork") {
+        match fork().expect("fork() failed") {
+            ForkResult::Parent { child } => {
+                // The PID namespace can only be persisted once its first
+                // process exists.
+                if let Some(persister) = persister {
+                    persister.persist();
+                }
+
+                // Wait for child and pass its exit status on.
+                let status = waitpid(child, None).expect("waitpid() failed");
+                process::exit(exit_code(status));
+            }
+            ForkResult::Child => {
+                // Fall through to code below.
+            }
+        }
+    } else if let Some(persister) = persister {
+        persister.persist();
+    }
+
+    if matches.is_present("mount-proc") {
+        mount_proc(
+            matches.value_of("mount-proc").unwrap_or("/proc"),
+            Propagation::from_matches(&matches),
+        );
+    }
+
     let cmd = matches.value_of("cmd").unwrap();
     let mut args_exec_owned: Vec<CString> = vec![CString::new(cmd).unwrap()];
     if matches.is_present("arg") {
@@ -77,5 +245,6 @@ fn main() {
             .for_each(|a| args_exec_owned.push(CString::new(a).unwrap()));
     }
     let args_exec: Vec<&CStr> = args_exec_owned.iter().map(CString::as_c_str).collect();
-    execvp(&args_exec[0], &args_exec).expect("exec() failed");
+    let Err(err) = execvp(args_exec[0], &args_exec);
+    panic!("exec() failed: {:?}", err);
 }
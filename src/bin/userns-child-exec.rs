@@ -1,65 +1,113 @@
-use clap::{crate_version, App, Arg};
-use nix::sched::{clone, CloneFlags};
-use nix::sys::signal::Signal;
-use nix::sys::wait::waitpid;
-use nix::unistd::{close, execvp, pipe, read, Pid};
+use caps::CapsHashSet;
+use clap::{crate_version, value_t, App, Arg};
+use namespaces_in_operation::capabilities::{parse_caps, CapChanges};
+use namespaces_in_operation::cgroup;
+use namespaces_in_operation::errpipe::{ErrorPipe, Step};
+use namespaces_in_operation::forward::{self, Forwarder};
+use namespaces_in_operation::mountns::{enter_rootfs, set_propagation, MountSpec, Propagation};
+use namespaces_in_operation::seccomp::{install, install_listener, notify_filter, Profile};
+use namespaces_in_operation::spawn::spawn;
+use namespaces_in_operation::stack::{self, ChildStack};
+use namespaces_in_operation::status::{describe, exit_code};
+use namespaces_in_operation::supervisor::{
+    listener_socket, recv_fd, send_fd, supervise, SUPERVISED,
+};
+use namespaces_in_operation::timens::{self, enter_new_time};
+use namespaces_in_operation::userns::{
+    has_setgid, read_subid_range, run_newidmap, set_setgroups, subid_map, update_map, IdMap,
+    Setgroups,
+};
+use namespaces_in_operation::utsns::{self, set_uts_names};
+use nix::fcntl::OFlag;
+use nix::sched::CloneFlags;
+use nix::unistd::{close, execvp, getegid, geteuid, pipe2, read, User};
 use std::ffi::{CStr, CString};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::unix::io::RawFd;
+use std::fs;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::process;
+use std::thread;
 
-// Update the mapping file 'map_file', with the value provided in
-// 'mapping', a string that defines a UID or GID mapping. A UID or
-// GID mapping consists of one or more newline-delimited records
-// of the form:
-//
-// ID_inside-ns    ID-outside-ns   length
-//
-// Requiring the user to supply a string that contains newlines is
-// of course inconvenient for command-line use. Thus, we permit the
-// use of commas to delimit records in this string, and replace them
-// with newlines before writing the string to the file.
-
-fn update_map(mapping: &str, map_file: &str) {
-    // Replace commas in mapping string with newlines
-    let mapping = String::from(mapping).replace(",", "\n");
-
-    let mut f = OpenOptions::new()
-        .write(true)
-        .open(map_file)
-        .expect(&format!("Error opening {}", map_file));
-    f.write(mapping.as_bytes())
-        .expect(&format!("Error writing to {}", map_file));
+// What the child sets up inside its new namespaces before executing its
+// command.
+struct Setup<'a> {
+    args: Vec<&'a CStr>,
+    propagation: Propagation,
+    mounts: Vec<MountSpec>,
+    // Root directory to pivot into.
+    rootfs: Option<&'a str>,
+    hostname: Option<&'a str>,
+    domainname: Option<&'a str>,
+    caps: CapChanges,
+    // Seccomp filter to install right before executing the command.
+    seccomp: Option<Vec<libc::sock_filter>>,
+    // Socket through which to pass a seccomp listener to the supervisor.
+    supervisor: Option<RawFd>,
+    // Offsets of CLOCK_MONOTONIC and CLOCK_BOOTTIME, if we are to enter a
+    // new time namespace.
+    time: Option<(Option<i64>, Option<i64>)>,
 }
 
-fn disable_setgroups(pid: &Pid) {
-    let path = format!("/proc/{}/setgroups", pid);
-    let mut f = OpenOptions::new()
-        .write(true)
-        .open(&path)
-        .expect(&format!("Error opening {}", path));
-    f.write(b"deny\n")
-        .expect(&format!("Error writing to {}", path));
-}
-
-fn child_func(args: &[&CStr], reader: RawFd, writer: RawFd) -> isize {
+fn child_func(
+    setup: &Setup,
+    reader: RawFd,
+    writer: RawFd,
+    errors: &ErrorPipe,
+    forwarder: &Forwarder,
+) -> isize {
     // Wait until the parent has updated the UID and GID mappings. See
     // the comment in `main()`. We wait for end of file on a pipe that will
     // be closed by the parent process once it has updated the mappings.
 
     // Close our descriptor for the write end of the pipe so that we see EOF
     // when parent closes its descriptor.
-    close(writer).expect("close() failed in child");
+    errors.check(Step::MapSync, close(writer));
 
     let mut buf: [u8; 1] = [0; 1];
-    read(reader, &mut buf).expect("read() from pipe failed in child");
+    errors.check(Step::MapSync, read(reader, &mut buf));
+    errors.check(Step::MapSync, close(reader));
 
-    execvp(&args[0], &args).expect("exec() failed");
-    0
-}
+    // Time namespaces cannot be requested through `clone()`, so we create
+    // one now that our user namespace (which is to own it) is set up.
+    if let Some((monotonic, boottime)) = setup.time {
+        errors.check(Step::Time, enter_new_time(monotonic, boottime));
+    }
+
+    errors.check(Step::Mount, set_propagation(setup.propagation));
+    match setup.rootfs {
+        Some(dir) => {
+            errors.check(Step::Mount, enter_rootfs(dir, &setup.mounts));
+        }
+        None => {
+            for spec in &setup.mounts {
+                errors.check(Step::Mount, spec.apply(""));
+            }
+        }
+    }
+    errors.check(
+        Step::Hostname,
+        set_uts_names(setup.hostname, setup.domainname),
+    );
+    errors.check(Step::Capabilities, setup.caps.apply());
+    // Before the seccomp filters, which might not allow it.
+    errors.check(Step::SignalMask, forwarder.restore());
+    // The supervisor filter goes first, since the profile might not allow
+    // handing its listener over. The order does not decide which filter
+    // wins: the kernel runs them all and takes the action of highest
+    // precedence, so that system calls denied by the profile fail without
+    // reaching the supervisor.
+    if let Some(sock) = setup.supervisor {
+        let listener = errors.check(Step::Seccomp, install_listener(&notify_filter(SUPERVISED)));
+        errors.check(Step::Seccomp, send_fd(sock, listener));
+        errors.check(Step::Seccomp, close(listener));
+    }
+    if let Some(prog) = &setup.seccomp {
+        errors.check(Step::Seccomp, install(prog));
+    }
 
-const STACK_LENGTH: usize = 1024 * 1024;
+    let Err(err) = execvp(setup.args[0], &setup.args);
+    errors.fail(Step::Exec, err)
+}
 
 fn main() {
     let matches = App::new("userns-child-exec")
@@ -76,6 +124,16 @@ fn main() {
                 .short("m")
                 .long("mount"),
         )
+        .arg(Propagation::arg())
+        .arg(
+            Arg::with_name("rootfs")
+                .help("pivot into DIR, with fresh /proc and /tmp mounts; implies --mount, requires --pid and a private or slave --propagation")
+                .long("rootfs")
+                .takes_value(true)
+                .requires("pid")
+                .value_name("DIR"),
+        )
+        .args(&MountSpec::args())
         .arg(
             Arg::with_name("net")
                 .help("unshare network namespace")
@@ -94,12 +152,26 @@ fn main() {
                 .short("u")
                 .long("uts"),
         )
+        .args(&utsns::args())
         .arg(
             Arg::with_name("user")
                 .help("unshare user namespace")
                 .short("U")
                 .long("user"),
         )
+        .arg(
+            Arg::with_name("cgroup")
+                .help("unshare cgroup namespace")
+                .short("C")
+                .long("cgroup"),
+        )
+        .arg(
+            Arg::with_name("time")
+                .help("unshare time namespace")
+                .short("T")
+                .long("time"),
+        )
+        .args(&timens::args())
         .arg(
             Arg::with_name("uid-map")
                 .help("UID map string for user namespace")
@@ -116,6 +188,65 @@ fn main() {
                 .value_name("MAP")
                 .long("gid-map"),
         )
+        .arg(
+            Arg::with_name("setgroups")
+                .help("whether setgroups(2) stays available in the new user namespace")
+                .long("setgroups")
+                .takes_value(true)
+                .possible_values(Setgroups::VALUES)
+                .default_value("auto")
+                .value_name("POLICY"),
+        )
+        .arg(
+            Arg::with_name("cap-drop")
+                .help("drop CAP (or all) from the bounding, inheritable and ambient sets")
+                .long("cap-drop")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CAP"),
+        )
+        .arg(
+            Arg::with_name("cap-add")
+                .help("raise CAP in the inheritable set")
+                .long("cap-add")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CAP"),
+        )
+        .arg(
+            Arg::with_name("ambient")
+                .help("raise CAP in the inheritable and ambient sets, to keep it across exec")
+                .long("ambient")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CAP"),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .help(
+                    "restrict system calls to those allowed by PROFILE (JSON or TOML, or default)",
+                )
+                .long("seccomp")
+                .takes_value(true)
+                .value_name("PROFILE"),
+        )
+        .arg(
+            Arg::with_name("supervise")
+                .help("emulate mknod(2) of /dev/null and similar devices from the parent")
+                .long("supervise"),
+        )
+        .arg(
+            Arg::with_name("map-subids")
+                .help("map 0 to our UID/GID and 1-65536 to our subordinate IDs; implies --user")
+                .long("map-subids")
+                .conflicts_with_all(&["uid-map", "gid-map"]),
+        )
+        .args(&cgroup::args())
+        .args(&forward::args())
+        .arg(stack::arg())
         .arg(
             Arg::with_name("verbose")
                 .help("verbose operation")
@@ -126,11 +257,13 @@ fn main() {
         .arg(Arg::with_name("arg").multiple(true))
         .get_matches();
 
+    let mounts = MountSpec::from_matches(&matches);
+
     let mut flags = CloneFlags::empty();
     if matches.is_present("ipc") {
         flags.set(CloneFlags::CLONE_NEWIPC, true)
     }
-    if matches.is_present("mount") {
+    if matches.is_present("mount") || matches.is_present("rootfs") || !mounts.is_empty() {
         flags.set(CloneFlags::CLONE_NEWNS, true)
     }
     if matches.is_present("net") {
@@ -139,15 +272,138 @@ fn main() {
     if matches.is_present("pid") {
         flags.set(CloneFlags::CLONE_NEWPID, true)
     }
-    if matches.is_present("uts") {
+    let (hostname, domainname) = utsns::from_matches(&matches);
+    if matches.is_present("uts") || hostname.is_some() || domainname.is_some() {
         flags.set(CloneFlags::CLONE_NEWUTS, true)
     }
-    if matches.is_present("user") {
+    if matches.is_present("user") || matches.is_present("map-subids") {
         flags.set(CloneFlags::CLONE_NEWUSER, true)
     }
+    if matches.is_present("cgroup") {
+        flags.set(CloneFlags::CLONE_NEWCGROUP, true)
+    }
+
+    let (monotonic, boottime) = timens::from_matches(&matches);
+    let time = matches.is_present("time") || monotonic.is_some() || boottime.is_some();
+
+    // Requires `--pid`, lest the fresh procfs show the processes of the
+    // host.
+    let rootfs = matches.value_of("rootfs");
+
+    let propagation = if flags.contains(CloneFlags::CLONE_NEWNS) {
+        Propagation::from_matches(&matches)
+    } else {
+        Propagation::Unchanged
+    };
+    // `pivot_root(2)` fails with EINVAL when the new root or the mounts
+    // above it are shared.
+    if rootfs.is_some() && matches!(propagation, Propagation::Shared | Propagation::Unchanged) {
+        clap::Error::with_description(
+            &format!("--rootfs cannot be used with --propagation {}", propagation),
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+
+    // Validate the UID and GID maps before creating the child.
+    let id_map = |arg: &str| {
+        matches.value_of(arg).map(|map| {
+            map.parse::<IdMap>().unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid --{}: {}", arg, err),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        })
+    };
+    let uid_map = id_map("uid-map");
+    let gid_map = id_map("gid-map");
+    let setgroups = value_t!(matches, "setgroups", Setgroups).unwrap().resolve();
+    let write_setgroups = flags.contains(CloneFlags::CLONE_NEWUSER)
+        && (matches.occurrences_of("setgroups") > 0 || gid_map.is_some());
+    if setgroups == Setgroups::Allow && gid_map.is_some() && !has_setgid() {
+        clap::Error::with_description(
+            "--setgroups allow requires CAP_SETGID to write --gid-map",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+
+    // With `--map-subids`, the maps come from `/etc/subuid` and
+    // `/etc/subgid`, and are written by the set-user-ID helpers from the
+    // shadow suite, since we may not write them ourselves.
+    let subid_maps = if matches.is_present("map-subids") {
+        let (uid, gid) = (geteuid(), getegid());
+        let name = match User::from_uid(uid) {
+            Ok(Some(user)) => user.name,
+            _ => uid.to_string(),
+        };
+        let subid_map = |path: &str, id: u32| {
+            read_subid_range(path, &name, uid.as_raw())
+                .and_then(|range| subid_map(id, range).map_err(|err| format!("{}: {}", path, err)))
+                .unwrap_or_else(|err| {
+                    eprintln!("userns-child-exec: {}", err);
+                    process::exit(1);
+                })
+        };
+        Some((
+            subid_map("/etc/subuid", uid.as_raw()),
+            subid_map("/etc/subgid", gid.as_raw()),
+        ))
+    } else {
+        None
+    };
+
+    let cap_set = |arg: &str| {
+        let mut set = CapsHashSet::new();
+        for name in matches.values_of(arg).into_iter().flatten() {
+            set.extend(parse_caps(name).unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid --{}: {}", arg, err),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            }));
+        }
+        set
+    };
+    let caps = CapChanges {
+        drop: cap_set("cap-drop"),
+        add: cap_set("cap-add"),
+        ambient: cap_set("ambient"),
+    };
+
+    // Compile the seccomp profile before creating the child, so that a
+    // broken profile is reported as such.
+    let seccomp = matches.value_of("seccomp").map(|path| {
+        Profile::load(path)
+            .and_then(|profile| profile.compile())
+            .unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid --seccomp: {}", err),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+    });
+
+    // With `--supervise`, the child hands some system calls to us through a
+    // seccomp listener, which it sends over this socket pair.
+    let supervisor = if matches.is_present("supervise") {
+        Some(listener_socket().expect("socketpair() failed"))
+    } else {
+        None
+    };
+
+    // Signals are passed on to the child, except that with `--kill-signal`,
+    // those asking us to terminate are replaced by the given one, followed
+    // by SIGKILL if need be.
+    let kill = forward::from_matches(&matches);
 
     let verbose = matches.is_present("verbose");
-    let mut child_stack: [u8; STACK_LENGTH] = [0; STACK_LENGTH];
+    let stack_size = stack::from_matches(&matches);
+    let mut child_stack = ChildStack::new(stack_size);
 
     let cmd = matches.value_of("cmd").unwrap();
     let mut args_exec_owned: Vec<CString> = vec![CString::new(cmd).unwrap()];
@@ -157,7 +413,22 @@ fn main() {
             .unwrap()
             .for_each(|a| args_exec_owned.push(CString::new(a).unwrap()));
     }
-    let args_exec: Vec<&CStr> = args_exec_owned.iter().map(CString::as_c_str).collect();
+    let setup = Setup {
+        args: args_exec_owned.iter().map(CString::as_c_str).collect(),
+        propagation,
+        mounts,
+        rootfs,
+        hostname,
+        domainname,
+        caps,
+        seccomp,
+        supervisor: supervisor.map(|(_, child_sock)| child_sock),
+        time: if time {
+            Some((monotonic, boottime))
+        } else {
+            None
+        },
+    };
 
     // We use a pipe to synchronize the parent and child, in order to
     // ensure that the parent sets the UID and GID maps before the child
@@ -169,47 +440,143 @@ fn main() {
     // user IDs (see the capabilities(7) man page for details of the
     // transformation of a process's capabilities during execve()).
 
-    let (reader, writer) = pipe().expect("pipe() failed");
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC).expect("pipe2() failed");
+
+    // The cgroup is created before the child, which is moved into it before
+    // it may execute its command.
+    let cgroup = cgroup::from_matches(&matches).unwrap_or_else(|err| {
+        eprintln!("userns-child-exec: {}", err);
+        process::exit(1);
+    });
+
+    // Any failure of the child before it executes its command is reported
+    // back to us through this pipe.
+    let errors = ErrorPipe::new();
 
-    let pid = clone(
-        Box::new(|| child_func(&args_exec, reader, writer)),
-        &mut child_stack,
+    // Before the child and any other threads are created; see `Forwarder`.
+    let mut forwarder = Forwarder::new(kill).expect("Error blocking signals");
+
+    // With `clone3()`, the child is created in its cgroup right away.
+    let cgroup_dir = cgroup.as_ref().map(|cgroup| {
+        File::open(cgroup.path()).map_err(|err| format!("{}: {}", cgroup.path().display(), err))
+    });
+    let cgroup_dir = match cgroup_dir.transpose() {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("userns-child-exec: {}", err);
+            cgroup::discard(cgroup);
+        }
+    };
+    let child = match spawn(
+        || child_func(&setup, reader, writer, &errors, &forwarder),
+        child_stack.as_mut_slice(),
         flags,
-        Some(Signal::SIGCHLD as i32),
-    )
-    .expect("clone() failed");
+        cgroup_dir.as_ref().map(File::as_raw_fd),
+    ) {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("userns-child-exec: clone() failed: {}", err);
+            cgroup::discard(cgroup);
+        }
+    };
+    drop(cgroup_dir);
+    close(reader).expect("close() failed");
+    let pid = child.pid;
 
     if verbose {
         println!(
-            "userns-child-exec: PID of child created by clone is {}",
+            "userns-child-exec: PID of child created by {} is {}",
+            child.created_by(),
             pid
         );
     }
 
-    // Update the UID and GID maps in the child.
+    if let Some((_, child_sock)) = supervisor {
+        close(child_sock).expect("close() failed");
+    }
+
+    // Update the UID and GID maps in the child. Should that fail, the child
+    // must not go on to execute its command without them.
+    let write_maps = || -> Result<(), String> {
+        // Unless we have CAP_SETGID, system call `setgroups(2)` has to be
+        // disabled, otherwise writing to the `gid_map` will fail. A policy
+        // given explicitly is applied with `--map-subids`, too.
+        if write_setgroups {
+            set_setgroups(setgroups, &format!("/proc/{}/setgroups", pid))?;
+        }
+        if let Some(uid_map) = &uid_map {
+            update_map(uid_map, &format!("/proc/{}/uid_map", pid))?;
+        }
+        if let Some(gid_map) = &gid_map {
+            update_map(gid_map, &format!("/proc/{}/gid_map", pid))?;
+        }
+        if let Some((uid_map, gid_map)) = &subid_maps {
+            run_newidmap("newuidmap", pid, uid_map)?;
+            run_newidmap("newgidmap", pid, gid_map)?;
+        }
+        Ok(())
+    };
+    if let Err(err) = write_maps() {
+        eprintln!("userns-child-exec: {}", err);
+        child.abort(cgroup);
+    }
 
-    if matches.is_present("uid-map") {
-        let map_path = format!("/proc/{}/uid_map", pid);
-        update_map(matches.value_of("uid-map").unwrap(), &map_path);
+    if let Some(Err(err)) = cgroup
+        .as_ref()
+        .filter(|_| !child.in_cgroup)
+        .map(|cgroup| cgroup.add(pid))
+    {
+        eprintln!("userns-child-exec: {}", err);
+        child.abort(cgroup);
     }
 
-    if matches.is_present("gid-map") {
-        // Disable system call `setgroups(2)`, otherwise writing to the
-        // `gid_map` will fail.
-        disable_setgroups(&pid);
-        let map_path = format!("/proc/{}/gid_map", pid);
-        update_map(matches.value_of("gid-map").unwrap(), &map_path);
+    if verbose && flags.contains(CloneFlags::CLONE_NEWUSER) {
+        let setgroups_file = format!("/proc/{}/setgroups", pid);
+        match fs::read_to_string(&setgroups_file) {
+            Ok(state) => println!("userns-child-exec: setgroups is {}", state.trim()),
+            Err(err) => println!("userns-child-exec: {}: {}", setgroups_file, err),
+        }
     }
 
     // Close the write end of the pipe, to signal to the child that we
-    // have updated the UID and GID maps.
+    // have updated the UID and GID maps, and moved it into its cgroup.
     close(writer).expect("close() failed");
 
-    // Parent process: Wait for child.
-    waitpid(pid, None).expect("waitpid() failed");
+    // The child sends its seccomp listener just before executing its
+    // command. If it fails before, we get end of file instead, and learn
+    // why below.
+    if let Some((sock, _)) = supervisor {
+        let listener = recv_fd(sock).expect("recvmsg() failed");
+        close(sock).expect("close() failed");
+        if let Some(listener) = listener {
+            thread::spawn(move || supervise(listener, verbose));
+        }
+    }
+
+    let failure = errors.wait();
+
+    // Parent process: Wait for child, and pass its exit status on.
+    let status = forwarder.wait(&child).expect("waitpid() failed");
+
+    if let Some(cgroup) = cgroup {
+        if verbose {
+            for usage in cgroup.usage() {
+                println!("userns-child-exec: {}", usage);
+            }
+        }
+        if let Err(err) = cgroup.remove() {
+            eprintln!("userns-child-exec: {}", err);
+        }
+    }
+
+    if let Some(failure) = failure {
+        eprintln!("userns-child-exec: {}", failure);
+        process::exit(failure.exit_code());
+    }
 
     if verbose {
+        println!("userns-child-exec: Child {}", describe(status));
         println!("userns-child-exec: Terminating");
     }
-    process::exit(0);
+    process::exit(exit_code(status));
 }
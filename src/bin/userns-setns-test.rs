@@ -1,15 +1,14 @@
+use clap::{crate_version, App, Arg};
+use namespaces_in_operation::stack::{self, ChildStack};
 use nix::sched::{clone, setns, CloneFlags};
 use nix::sys::signal::Signal;
 use nix::sys::wait::waitpid;
-use std::env;
 use std::fs::{read_link, OpenOptions};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::process;
 use std::thread;
 use std::time::Duration;
 
-const STACK_SIZE: usize = 1024 * 1024;
-
 // Try to join the user namespace identified by the file descriptor `fd`.
 // `pname` is a per-process string that the caller may use to distinguish
 // information messages displayed by this function.
@@ -40,24 +39,31 @@ fn child_func(fd: RawFd) -> isize {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} /proc/PID/ns/user", args[0]);
-        process::exit(1);
-    }
+    let matches = App::new("userns-setns-test")
+        .version(crate_version!())
+        .arg(stack::arg())
+        .arg(
+            Arg::with_name("ns-file")
+                .help("user namespace file to join, such as /proc/PID/ns/user")
+                .index(1)
+                .required(true),
+        )
+        .get_matches();
+
+    let stack_size = stack::from_matches(&matches);
 
     // Open user namespace file specified on command line.
     let file = OpenOptions::new()
         .read(true)
-        .open(&args[1])
+        .open(matches.value_of("ns-file").unwrap())
         .expect("open() failed");
     let fd = file.as_raw_fd();
 
     // Create child process in new user namespace.
-    let mut child_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let mut child_stack = ChildStack::new(stack_size);
     let pid = clone(
         Box::new(|| child_func(fd)),
-        &mut child_stack,
+        child_stack.as_mut_slice(),
         CloneFlags::CLONE_NEWUSER,
         Some(Signal::SIGCHLD as i32),
     )
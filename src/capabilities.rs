@@ -0,0 +1,141 @@
+use caps::errors::ErrorKind;
+use caps::{CapSet, Capability, CapsHashSet};
+use nix::errno::Errno;
+use std::fs;
+
+const CAP_LAST_CAP: &str = "/proc/sys/kernel/cap_last_cap";
+
+// Parse a capability name, with or without its `CAP_` prefix and in any
+// case, or `all` for all of them.
+
+pub fn parse_caps(name: &str) -> Result<CapsHashSet, String> {
+    if name.eq_ignore_ascii_case("all") {
+        return Ok(caps::all());
+    }
+    let cap: Capability = caps::to_canonical(name)
+        .parse()
+        .map_err(|_| format!("unknown capability: {}", name))?;
+    Ok(vec![cap].into_iter().collect())
+}
+
+fn to_nix(err: caps::errors::Error) -> nix::Error {
+    match err.kind() {
+        ErrorKind::Sys(errno) => nix::Error::Sys(Errno::from_i32(errno.0)),
+        _ => nix::Error::Sys(Errno::EINVAL),
+    }
+}
+
+// Changes to the capability sets of a child before it executes its
+// command.
+//
+// Across `execve(2)`, a process keeps the capabilities in its ambient set,
+// and a process with UID 0 gets those in its bounding set (see
+// capabilities(7)). Dropping a capability thus removes it from the bounding,
+// inheritable and ambient sets. Adding one raises it in the inheritable set,
+// which matters for programs with file capabilities. Raising it in the
+// ambient set as well lets a program run by a nonzero UID keep it. Adding
+// takes precedence over dropping, so that `--cap-drop all --cap-add CAP`
+// keeps CAP only.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CapChanges {
+    pub drop: CapsHashSet,
+    pub add: CapsHashSet,
+    pub ambient: CapsHashSet,
+}
+
+impl CapChanges {
+    // Apply these changes to the calling thread, which needs CAP_SETPCAP in
+    // its user namespace, and the added capabilities in its permitted set.
+
+    pub fn apply(&self) -> nix::Result<()> {
+        let (dropped, kept) = self.resolve();
+        for &cap in &dropped {
+            caps::drop(None, CapSet::Ambient, cap).map_err(to_nix)?;
+            caps::drop(None, CapSet::Inheritable, cap).map_err(to_nix)?;
+            caps::drop(None, CapSet::Bounding, cap).map_err(to_nix)?;
+        }
+
+        // The kernel may know of more capabilities than the `caps` crate
+        // does. Dropping all of them has to take those into account too.
+        if self.drop.is_superset(&caps::all()) {
+            drop_unknown_from_bounding()?;
+        }
+
+        for &cap in &kept {
+            caps::raise(None, CapSet::Inheritable, cap).map_err(to_nix)?;
+        }
+        for &cap in &self.ambient {
+            caps::raise(None, CapSet::Ambient, cap).map_err(to_nix)?;
+        }
+        Ok(())
+    }
+
+    // The capabilities to drop, and those to raise in the inheritable set.
+
+    fn resolve(&self) -> (CapsHashSet, CapsHashSet) {
+        let kept: CapsHashSet = self.add.union(&self.ambient).cloned().collect();
+        let dropped = self.drop.difference(&kept).cloned().collect();
+        (dropped, kept)
+    }
+}
+
+// Drop the capabilities unknown to the `caps` crate from the bounding set.
+
+fn drop_unknown_from_bounding() -> nix::Result<()> {
+    let last_cap: libc::c_ulong = fs::read_to_string(CAP_LAST_CAP)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0);
+    let known = caps::all().iter().map(|cap| cap.index()).max().unwrap_or(0);
+    for cap in (known as libc::c_ulong + 1)..=last_cap {
+        let res = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) };
+        Errno::result(res)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(caps: &[Capability]) -> CapsHashSet {
+        caps.iter().cloned().collect()
+    }
+
+    #[test]
+    fn parses_capability_names() {
+        let net_admin = Ok(set(&[Capability::CAP_NET_ADMIN]));
+        assert_eq!(parse_caps("CAP_NET_ADMIN"), net_admin);
+        assert_eq!(parse_caps("net_admin"), net_admin);
+        assert_eq!(parse_caps("Cap_Net_Admin"), net_admin);
+        assert_eq!(parse_caps("all"), Ok(caps::all()));
+        assert_eq!(parse_caps("ALL"), Ok(caps::all()));
+        assert!(parse_caps("net_wizard").is_err());
+        assert!(parse_caps("").is_err());
+    }
+
+    #[test]
+    fn adding_wins_over_dropping() {
+        let changes = CapChanges {
+            drop: caps::all(),
+            add: set(&[Capability::CAP_NET_ADMIN]),
+            ambient: set(&[Capability::CAP_SYS_TIME]),
+        };
+        let (dropped, kept) = changes.resolve();
+        assert_eq!(
+            kept,
+            set(&[Capability::CAP_NET_ADMIN, Capability::CAP_SYS_TIME])
+        );
+        assert!(dropped.is_disjoint(&kept));
+        assert_eq!(dropped.len(), caps::all().len() - 2);
+
+        let changes = CapChanges {
+            drop: set(&[Capability::CAP_CHOWN]),
+            ..CapChanges::default()
+        };
+        assert_eq!(
+            changes.resolve(),
+            (set(&[Capability::CAP_CHOWN]), CapsHashSet::new())
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use clap::{Arg, ArgMatches};
+use nix::errno::Errno;
+use nix::unistd::Pid;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+// How often, and how long apart, we try to remove a cgroup whose processes
+// are still exiting.
+const REMOVE_ATTEMPTS: u32 = 100;
+const REMOVE_INTERVAL: Duration = Duration::from_millis(10);
+
+// Parse a memory limit in bytes, optionally with a `K`, `M` or `G` suffix
+// (powers of 1024), or `max`.
+
+pub fn parse_memory_max(s: &str) -> Result<String, String> {
+    if s == "max" {
+        return Ok(s.to_string());
+    }
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(|n| n.to_string())
+        .ok_or_else(|| format!("'{}' is not a size", s))
+}
+
+// Parse a limit on the number of processes, or `max`.
+
+pub fn parse_pids_max(s: &str) -> Result<String, String> {
+    if s == "max" || s.parse::<u32>().is_ok() {
+        Ok(s.to_string())
+    } else {
+        Err(format!("'{}' is not a number", s))
+    }
+}
+
+// Parse a CPU bandwidth limit, given as QUOTA[/PERIOD] in microseconds,
+// where QUOTA may be `max`, into the format of `cpu.max`.
+
+pub fn parse_cpu_max(s: &str) -> Result<String, String> {
+    let mut parts = s.splitn(2, '/');
+    let quota = parts.next().unwrap_or("");
+    let positive = |s: &str| s.parse::<u64>().is_ok_and(|n| n > 0);
+    if quota != "max" && !positive(quota) {
+        return Err(format!("'{}' is not a quota", quota));
+    }
+    match parts.next() {
+        None => Ok(quota.to_string()),
+        Some(period) if positive(period) => Ok(format!("{} {}", quota, period)),
+        Some(period) => Err(format!("'{}' is not a period", period)),
+    }
+}
+
+// Limits to write to the interface files of a cgroup, in the formats
+// returned by the `parse_*()` functions above.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub memory_max: Option<String>,
+    pub pids_max: Option<String>,
+    pub cpu_max: Option<String>,
+}
+
+// The `--cgroup-path` option, and the options setting limits for the
+// cgroup.
+
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("cgroup-path")
+            .help("run the child in new cgroup PATH, relative to the cgroup v2 mount")
+            .long("cgroup-path")
+            .takes_value(true)
+            .value_name("PATH"),
+        Arg::with_name("memory-max")
+            .help("memory limit of the cgroup, in bytes or with a K, M or G suffix")
+            .long("memory-max")
+            .takes_value(true)
+            .requires("cgroup-path")
+            .value_name("SIZE"),
+        Arg::with_name("pids-max")
+            .help("limit on the number of processes in the cgroup")
+            .long("pids-max")
+            .takes_value(true)
+            .requires("cgroup-path")
+            .value_name("N"),
+        Arg::with_name("cpu-max")
+            .help("CPU bandwidth limit of the cgroup, in microseconds per period")
+            .long("cpu-max")
+            .takes_value(true)
+            .requires("cgroup-path")
+            .value_name("QUOTA[/PERIOD]"),
+    ]
+}
+
+// Create the cgroup requested with the options of `args()`, if any. Exits
+// with a usage error if a limit is invalid.
+
+pub fn from_matches(matches: &ArgMatches) -> Result<Option<Cgroup>, String> {
+    let limit = |arg: &str, parse: fn(&str) -> Result<String, String>| {
+        matches.value_of(arg).map(|value| {
+            parse(value).unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid --{}: {}", arg, err),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        })
+    };
+    let limits = Limits {
+        memory_max: limit("memory-max", parse_memory_max),
+        pids_max: limit("pids-max", parse_pids_max),
+        cpu_max: limit("cpu-max", parse_cpu_max),
+    };
+    matches
+        .value_of("cgroup-path")
+        .map(|path| Cgroup::create(path, &limits))
+        .transpose()
+}
+
+// The mount point of the cgroup v2 hierarchy, which on hybrid systems is
+// not /sys/fs/cgroup itself.
+
+fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string(MOUNTINFO).ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (fields, fs_type) = line.split_at(line.find(" - ")?);
+        if fs_type[3..].split(' ').next()? != "cgroup2" {
+            return None;
+        }
+        fields.split(' ').nth(4).map(PathBuf::from)
+    })
+}
+
+// A leaf cgroup, created for a child and removed once the child is done.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    // Create cgroup 'path', which is relative to the cgroup v2 mount point
+    // unless absolute, and apply 'limits' to it. The controllers for the
+    // limits have to be enabled in the `cgroup.subtree_control` file of its
+    // parent.
+
+    pub fn create(path: &str, limits: &Limits) -> Result<Cgroup, String> {
+        let path = if Path::new(path).is_absolute() {
+            PathBuf::from(path)
+        } else {
+            cgroup2_mount()
+                .ok_or_else(|| "no cgroup v2 hierarchy mounted".to_string())?
+                .join(path)
+        };
+        fs::create_dir(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        let cgroup = Cgroup { path };
+        let files = [
+            ("memory.max", &limits.memory_max),
+            ("pids.max", &limits.pids_max),
+            ("cpu.max", &limits.cpu_max),
+        ];
+        for (file, value) in files.iter() {
+            if let Some(value) = value {
+                if let Err(err) = cgroup.write(file, value) {
+                    let _ = fs::remove_dir(&cgroup.path);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), String> {
+        let path = self.path.join(file);
+        // Interface files are never created, and trying to do so fails with
+        // EACCES rather than ENOENT.
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(value.as_bytes()))
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => format!(
+                    "{}: {} (is its controller enabled in the parent's cgroup.subtree_control?)",
+                    path.display(),
+                    err
+                ),
+                _ => format!("{}: {}", path.display(), err),
+            })
+    }
+
+    // Move process 'pid' into this cgroup.
+
+    pub fn add(&self, pid: Pid) -> Result<(), String> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    // Resource usage to report: the peak memory usage, and the first lines
+    // of `cpu.stat`, which tell the CPU time used.
+
+    pub fn usage(&self) -> Vec<String> {
+        let mut usage = Vec::new();
+        if let Ok(peak) = fs::read_to_string(self.path.join("memory.peak")) {
+            usage.push(format!("memory.peak {}", peak.trim()));
+        }
+        if let Ok(stat) = fs::read_to_string(self.path.join("cpu.stat")) {
+            let times: Vec<&str> = stat.lines().take(3).collect();
+            usage.push(format!("cpu.stat {}", times.join(", ")));
+        }
+        usage
+    }
+
+    // Remove this cgroup. Descendants of the child may still be in it; if
+    // so, they are killed (Linux 5.14), and given some time to exit.
+
+    pub fn remove(self) -> Result<(), String> {
+        let mut killed = false;
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => return Ok(()),
+                Err(err) if err.raw_os_error() == Some(Errno::EBUSY as i32) => {
+                    if !killed {
+                        killed = true;
+                        let _ = self.write("cgroup.kill", "1");
+                    }
+                    thread::sleep(REMOVE_INTERVAL);
+                }
+                Err(err) => return Err(format!("{}: {}", self.path.display(), err)),
+            }
+        }
+        Err(format!(
+            "{}: {}",
+            self.path.display(),
+            std::io::Error::from_raw_os_error(Errno::EBUSY as i32)
+        ))
+    }
+}
+
+// Remove the cgroup created for a child, if any, after we failed to create
+// or set up the child, and exit.
+
+pub fn discard(cgroup: Option<Cgroup>) -> ! {
+    if let Some(cgroup) = cgroup {
+        let _ = cgroup.remove();
+    }
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(parse_memory_max("512M").unwrap(), "536870912");
+        assert_eq!(parse_memory_max("max").unwrap(), "max");
+        assert!(parse_memory_max("lots").is_err());
+        assert_eq!(parse_pids_max("64").unwrap(), "64");
+        assert!(parse_pids_max("-1").is_err());
+        assert_eq!(parse_cpu_max("50000").unwrap(), "50000");
+        assert_eq!(parse_cpu_max("max/200000").unwrap(), "max 200000");
+        assert!(parse_cpu_max("0").is_err());
+        assert!(parse_cpu_max("50000/").is_err());
+    }
+}
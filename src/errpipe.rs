@@ -0,0 +1,192 @@
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::unistd::{close, pipe2, read, write};
+use std::fmt;
+use std::os::unix::io::RawFd;
+
+// Steps of the setup of a cloned child that may fail before it manages to
+// execute its command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    MapSync,
+    Sync,
+    Time,
+    Mount,
+    Hostname,
+    Capabilities,
+    SignalMask,
+    Seccomp,
+    Exec,
+}
+
+impl Step {
+    const ALL: &'static [Step] = &[
+        Step::MapSync,
+        Step::Sync,
+        Step::Time,
+        Step::Mount,
+        Step::Hostname,
+        Step::Capabilities,
+        Step::SignalMask,
+        Step::Seccomp,
+        Step::Exec,
+    ];
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Step::MapSync => "map sync",
+            Step::Sync => "sync",
+            Step::Time => "time namespace",
+            Step::Mount => "mount",
+            Step::Hostname => "hostname",
+            Step::Capabilities => "capabilities",
+            Step::SignalMask => "signal mask",
+            Step::Seccomp => "seccomp",
+            Step::Exec => "exec",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// A failure reported by the child through an `ErrorPipe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChildError {
+    pub step: Step,
+    pub errno: Errno,
+}
+
+impl ChildError {
+    // The exit status with which to report this failure, following the
+    // conventions of shells and container runtimes: 127 if the command was
+    // not found, 126 if it could not be executed, and 125 if the child
+    // failed before even trying.
+    pub fn exit_code(&self) -> i32 {
+        match (self.step, self.errno) {
+            (Step::Exec, Errno::ENOENT) => 127,
+            (Step::Exec, _) => 126,
+            _ => 125,
+        }
+    }
+}
+
+impl fmt::Display for ChildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed in child: {}", self.step, self.errno)
+    }
+}
+
+// A close-on-exec pipe through which a cloned child reports why its setup
+// failed. The parent sees end of file without any data once the child has
+// executed its command, since that closes the child's write end.
+pub struct ErrorPipe {
+    reader: RawFd,
+    writer: RawFd,
+}
+
+impl ErrorPipe {
+    pub fn new() -> ErrorPipe {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).expect("pipe2() failed");
+        ErrorPipe { reader, writer }
+    }
+
+    // In the child: report that 'step' failed with 'err', and exit.
+
+    pub fn fail(&self, step: Step, err: nix::Error) -> ! {
+        let errno = err.as_errno().unwrap_or(Errno::UnknownErrno);
+        let mut buf = [0; 5];
+        buf[0] = step as u8;
+        buf[1..].copy_from_slice(&(errno as i32).to_ne_bytes());
+        let _ = write(self.writer, &buf);
+        unsafe { libc::_exit(1) }
+    }
+
+    // In the child: unwrap 'result', or report that 'step' failed.
+
+    pub fn check<T>(&self, step: Step, result: nix::Result<T>) -> T {
+        result.unwrap_or_else(|err| self.fail(step, err))
+    }
+
+    // In the parent, once the child has been created: wait until the child
+    // has either executed its command or reported a failure.
+
+    pub fn wait(self) -> Option<ChildError> {
+        close(self.writer).expect("close() failed");
+
+        let mut buf = [0; 5];
+        let mut len = 0;
+        while len < buf.len() {
+            match read(self.reader, &mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => panic!("read() from error pipe failed: {}", err),
+            }
+        }
+        close(self.reader).expect("close() failed");
+
+        if len < buf.len() {
+            return None;
+        }
+        let mut errno = [0; 4];
+        errno.copy_from_slice(&buf[1..]);
+        Some(ChildError {
+            step: *Step::ALL.get(buf[0] as usize)?,
+            errno: Errno::from_i32(i32::from_ne_bytes(errno)),
+        })
+    }
+}
+
+impl Default for ErrorPipe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    // Run 'child' in a forked child, and return what the parent learns
+    // from the error pipe.
+    fn report(child: impl FnOnce(&ErrorPipe)) -> Option<ChildError> {
+        let errors = ErrorPipe::new();
+        match fork().expect("fork() failed") {
+            ForkResult::Child => {
+                child(&errors);
+                unsafe { libc::_exit(0) }
+            }
+            ForkResult::Parent { child } => {
+                let failure = errors.wait();
+                assert!(matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, _))));
+                failure
+            }
+        }
+    }
+
+    #[test]
+    fn reports_failed_step_and_errno() {
+        let failure = report(|errors| errors.fail(Step::Mount, nix::Error::Sys(Errno::EACCES)));
+        assert_eq!(
+            failure,
+            Some(ChildError {
+                step: Step::Mount,
+                errno: Errno::EACCES,
+            })
+        );
+        assert_eq!(failure.unwrap().exit_code(), 125);
+
+        let failure = report(|errors| {
+            errors.check(Step::Exec, Err::<(), _>(nix::Error::Sys(Errno::ENOENT)));
+        });
+        assert_eq!(failure.map(|failure| failure.exit_code()), Some(127));
+    }
+
+    #[test]
+    fn reports_nothing_on_clean_exit() {
+        assert_eq!(report(|_| {}), None);
+    }
+}
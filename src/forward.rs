@@ -0,0 +1,212 @@
+use crate::spawn::Child;
+use clap::{value_t, Arg, ArgMatches};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{SigSet, SigmaskHow, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+// Signals we pass on to the child.
+pub const FORWARDED: &[Signal] = &[
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+];
+
+// `si_code` of signals sent by the kernel, such as those generated by the
+// terminal (not in our version of the `libc` crate).
+const SI_KERNEL: i32 = 0x80;
+
+// Signals asking us to terminate, which `--kill-signal` applies to.
+const TERMINATING: &[Signal] = &[
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+];
+
+// Parse a signal given by name, with or without its `SIG` prefix and in any
+// case, or by number.
+
+pub fn parse_signal(s: &str) -> Result<Signal, String> {
+    if let Ok(n) = s.parse::<libc::c_int>() {
+        return Signal::try_from(n).map_err(|_| format!("unknown signal: {}", s));
+    }
+    let name = s.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse().map_err(|_| format!("unknown signal: {}", s))
+}
+
+// The `--kill-signal` and `--kill-after` options.
+
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("kill-signal")
+            .help(
+                "on SIGINT, SIGTERM, SIGHUP or SIGQUIT, send SIGNAL to the child, \
+                 and SIGKILL if it is still running after --kill-after seconds",
+            )
+            .long("kill-signal")
+            .takes_value(true)
+            .value_name("SIGNAL"),
+        Arg::with_name("kill-after")
+            .help("grace period before sending SIGKILL, with --kill-signal")
+            .long("kill-after")
+            .takes_value(true)
+            .default_value("10")
+            .value_name("SECONDS"),
+    ]
+}
+
+// The signal given with `--kill-signal`, if any, along with the grace
+// period of `--kill-after`, as taken by `Forwarder::new()`. Exits with a
+// usage error if either is invalid.
+
+pub fn from_matches(matches: &ArgMatches) -> Option<(Signal, Duration)> {
+    matches.value_of("kill-signal").map(|value| {
+        let signal = parse_signal(value).unwrap_or_else(|err| {
+            clap::Error::with_description(
+                &format!("Invalid --kill-signal: {}", err),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+        let grace = value_t!(matches, "kill-after", u64).unwrap_or_else(|e| e.exit());
+        (signal, Duration::from_secs(grace))
+    })
+}
+
+// Passes the signals in FORWARDED on to a child while we wait for it.
+//
+// The signals (and SIGCHLD) are blocked, and read from a signalfd instead.
+// Since threads and children inherit the signal mask of their creator, a
+// `Forwarder` has to be created before any other threads, lest those get
+// the signals, and before the child, lest the signals kill us while we set
+// it up. The child restores its signal mask with `restore()`.
+//
+// Signals generated by the terminal already reach the child, which is in
+// our process group, and are not forwarded again.
+pub struct Forwarder {
+    signals: SignalFd,
+    // Signal to send instead of a terminating one, and how long to wait
+    // before sending SIGKILL after it.
+    kill: Option<(Signal, Duration)>,
+    // Signal mask from before we blocked the signals.
+    old_mask: SigSet,
+}
+
+impl Forwarder {
+    pub fn new(kill: Option<(Signal, Duration)>) -> nix::Result<Forwarder> {
+        let mut mask = SigSet::empty();
+        for &signal in FORWARDED {
+            mask.add(signal);
+        }
+        mask.add(Signal::SIGCHLD);
+        let old_mask = mask.thread_swap_mask(SigmaskHow::SIG_BLOCK)?;
+        let signals = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC)?;
+        Ok(Forwarder {
+            signals,
+            kill,
+            old_mask,
+        })
+    }
+
+    // Restore the signal mask from before this `Forwarder` was created. This
+    // is for the child, before it executes its command.
+
+    pub fn restore(&self) -> nix::Result<()> {
+        self.old_mask.thread_set_mask()
+    }
+
+    // Wait for 'child' to terminate, forwarding the signals we get to it in
+    // the meantime. A child created without a pidfd is watched through
+    // SIGCHLD instead.
+
+    pub fn wait(&mut self, child: &Child) -> nix::Result<WaitStatus> {
+        let mut deadline: Option<Instant> = None;
+        loop {
+            if child.pidfd.is_none() {
+                match waitpid(child.pid, Some(WaitPidFlag::WNOHANG))? {
+                    WaitStatus::StillAlive => {}
+                    status => return Ok(status),
+                }
+            }
+
+            let timeout = deadline.map_or(-1, |deadline| {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+            });
+            let mut fds = vec![PollFd::new(self.signals.as_raw_fd(), PollFlags::POLLIN)];
+            if let Some(pidfd) = &child.pidfd {
+                fds.push(PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN));
+            }
+            match poll(&mut fds, timeout) {
+                Ok(0) => {
+                    // The grace period is over.
+                    deadline = None;
+                    let _ = child.signal(Signal::SIGKILL);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => return Err(err),
+            }
+
+            let ready = |fd: &PollFd| fd.revents().is_some_and(|revents| !revents.is_empty());
+            if fds.get(1).is_some_and(ready) {
+                return child.wait();
+            }
+            if !ready(&fds[0]) {
+                continue;
+            }
+            let (signal, from_kernel) = match self.signals.read_signal()? {
+                Some(info) => (
+                    Signal::try_from(info.ssi_signo as libc::c_int)?,
+                    info.ssi_code == SI_KERNEL,
+                ),
+                None => continue,
+            };
+            if signal == Signal::SIGCHLD {
+                continue;
+            }
+            match self.kill {
+                Some((kill_signal, grace)) if TERMINATING.contains(&signal) => {
+                    let _ = child.signal(kill_signal);
+                    if deadline.is_none() {
+                        deadline = Some(Instant::now() + grace);
+                    }
+                }
+                _ if from_kernel => {}
+                // The child may have terminated in the meantime.
+                _ => {
+                    let _ = child.signal(signal);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_signals() {
+        assert_eq!(parse_signal("SIGTERM"), Ok(Signal::SIGTERM));
+        assert_eq!(parse_signal("term"), Ok(Signal::SIGTERM));
+        assert_eq!(parse_signal("9"), Ok(Signal::SIGKILL));
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("0").is_err());
+    }
+}
@@ -0,0 +1,19 @@
+// Helpers shared by the programs in `src/bin`.
+
+pub mod capabilities;
+pub mod cgroup;
+pub mod errpipe;
+pub mod forward;
+pub mod mountns;
+pub mod ns;
+pub mod persist;
+pub mod pidfd;
+pub mod seccomp;
+pub mod spawn;
+pub mod stack;
+pub mod status;
+pub mod supervisor;
+pub mod syscalls;
+pub mod timens;
+pub mod userns;
+pub mod utsns;
@@ -0,0 +1,468 @@
+use clap::{value_t, Arg, ArgMatches};
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::unistd::{chdir, pivot_root};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+const NONE: Option<&'static [u8]> = None;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+// Propagation type applied to all mounts of a new mount namespace. A new
+// mount namespace gets copies of the parent's mounts, including their
+// propagation type; with shared propagation, mount events inside the
+// namespace would then reach the parent namespace, and vice versa.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Propagation {
+    Private,
+    Slave,
+    Shared,
+    Unchanged,
+}
+
+impl Propagation {
+    pub const VALUES: &'static [&'static str] = &["private", "slave", "shared", "unchanged"];
+
+    // The `--propagation` option.
+
+    pub fn arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("propagation")
+            .help("propagation type of mounts in the new mount namespace")
+            .long("propagation")
+            .takes_value(true)
+            .possible_values(Propagation::VALUES)
+            .default_value("private")
+            .value_name("MODE")
+    }
+
+    // The propagation type given with `--propagation`, or its default.
+
+    pub fn from_matches(matches: &ArgMatches) -> Propagation {
+        value_t!(matches, "propagation", Propagation).unwrap()
+    }
+}
+
+impl FromStr for Propagation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(Propagation::Private),
+            "slave" => Ok(Propagation::Slave),
+            "shared" => Ok(Propagation::Shared),
+            "unchanged" => Ok(Propagation::Unchanged),
+            _ => Err(format!("unsupported propagation mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Propagation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Propagation::Private => "private",
+            Propagation::Slave => "slave",
+            Propagation::Shared => "shared",
+            Propagation::Unchanged => "unchanged",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn io_error(err: std::io::Error) -> nix::Error {
+    nix::Error::Sys(Errno::from_i32(err.raw_os_error().unwrap_or(libc::EIO)))
+}
+
+// Recursively change the propagation type of all mounts under `/`. This
+// has to be called from inside the new mount namespace.
+
+pub fn set_propagation(propagation: Propagation) -> nix::Result<()> {
+    let flags = match propagation {
+        Propagation::Private => MsFlags::MS_PRIVATE,
+        Propagation::Slave => MsFlags::MS_SLAVE,
+        Propagation::Shared => MsFlags::MS_SHARED,
+        Propagation::Unchanged => return Ok(()),
+    };
+    mount(NONE, "/", NONE, MsFlags::MS_REC | flags, NONE)
+}
+
+// Mount destinations are joined to the path of the new root, so they have
+// to be absolute and must not climb out of it.
+
+fn check_dst(dst: &str) -> Result<(), String> {
+    let path = Path::new(dst);
+    if !path.is_absolute() {
+        return Err(format!("DST {} is not an absolute path", dst));
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("DST {} contains '..'", dst));
+    }
+    Ok(())
+}
+
+// A mount to be made in a new mount namespace, as given by options
+// `--bind SRC:DST[:ro]`, `--ro-bind SRC:DST` and `--tmpfs DST[:OPTIONS]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountSpec {
+    Bind {
+        src: String,
+        dst: String,
+        readonly: bool,
+    },
+    Tmpfs {
+        dst: String,
+        options: Option<String>,
+    },
+}
+
+impl MountSpec {
+    // The `--bind`, `--ro-bind` and `--tmpfs` options.
+
+    pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("bind")
+                .help("bind-mount SRC on DST, read-only with :ro; implies --mount")
+                .long("bind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("SRC:DST[:ro]"),
+            Arg::with_name("ro-bind")
+                .help("bind-mount SRC on DST, read-only; implies --mount")
+                .long("ro-bind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("SRC:DST"),
+            Arg::with_name("tmpfs")
+                .help("mount a tmpfs on DST, with OPTIONS such as size=64m; implies --mount")
+                .long("tmpfs")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DST[:OPTIONS]"),
+        ]
+    }
+
+    // The mounts requested with the options of `args()`, in the order
+    // given, since later ones may be nested in earlier ones. Exits with a
+    // usage error if any of them is invalid.
+
+    pub fn from_matches(matches: &ArgMatches) -> Vec<MountSpec> {
+        let mut mounts: Vec<(usize, MountSpec)> = Vec::new();
+        for &kind in &["bind", "ro-bind", "tmpfs"] {
+            if let (Some(indices), Some(values)) =
+                (matches.indices_of(kind), matches.values_of(kind))
+            {
+                for (index, value) in indices.zip(values) {
+                    let spec = MountSpec::parse(kind, value).unwrap_or_else(|err| {
+                        clap::Error::with_description(
+                            &format!("Invalid --{} '{}': {}", kind, value, err),
+                            clap::ErrorKind::InvalidValue,
+                        )
+                        .exit()
+                    });
+                    mounts.push((index, spec));
+                }
+            }
+        }
+        mounts.sort_by_key(|&(index, _)| index);
+        mounts.into_iter().map(|(_, spec)| spec).collect()
+    }
+
+    // Parse the value of option `--bind`, `--ro-bind` or `--tmpfs`, as
+    // named by 'kind'.
+
+    pub fn parse(kind: &str, s: &str) -> Result<MountSpec, String> {
+        match kind {
+            "bind" | "ro-bind" => {
+                let fields: Vec<&str> = s.split(':').collect();
+                let (src, dst, readonly) = match (kind, fields.as_slice()) {
+                    (_, &[src, dst]) => (src, dst, kind == "ro-bind"),
+                    ("bind", &[src, dst, "ro"]) => (src, dst, true),
+                    ("bind", _) => return Err("expected SRC:DST[:ro]".to_string()),
+                    _ => return Err("expected SRC:DST".to_string()),
+                };
+                if src.is_empty() || dst.is_empty() {
+                    return Err("empty SRC or DST".to_string());
+                }
+                check_dst(dst)?;
+                Ok(MountSpec::Bind {
+                    src: src.to_string(),
+                    dst: dst.to_string(),
+                    readonly,
+                })
+            }
+            "tmpfs" => {
+                let mut fields = s.splitn(2, ':');
+                let dst = fields.next().unwrap_or("");
+                if dst.is_empty() {
+                    return Err("expected DST[:OPTIONS]".to_string());
+                }
+                check_dst(dst)?;
+                Ok(MountSpec::Tmpfs {
+                    dst: dst.to_string(),
+                    options: fields.next().map(str::to_string),
+                })
+            }
+            _ => Err(format!("unsupported mount kind: {}", kind)),
+        }
+    }
+
+    pub fn dst(&self) -> &str {
+        match self {
+            MountSpec::Bind { dst, .. } | MountSpec::Tmpfs { dst, .. } => dst,
+        }
+    }
+
+    // Create the destination of this mount under 'root', along with its
+    // parent directories: a file for a bind mount of a file, and a
+    // directory otherwise.
+
+    fn create_dst(&self, root: &str) -> nix::Result<()> {
+        let dst = PathBuf::from(format!("{}{}", root, self.dst()));
+        let is_file = match self {
+            MountSpec::Bind { src, .. } => !Path::new(src).is_dir(),
+            MountSpec::Tmpfs { .. } => false,
+        };
+        let res = match dst.parent() {
+            Some(parent) if is_file => fs::create_dir_all(parent).and_then(|()| {
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&dst)
+                    .map(drop)
+            }),
+            _ => fs::create_dir_all(&dst),
+        };
+        res.map_err(io_error)
+    }
+
+    // Make this mount, with its destination under 'root' (the empty string
+    // for the current root directory).
+
+    pub fn apply(&self, root: &str) -> nix::Result<()> {
+        match self {
+            MountSpec::Bind { src, dst, readonly } => {
+                let dst = format!("{}{}", root, dst);
+                mount(
+                    Some(src.as_str()),
+                    dst.as_str(),
+                    NONE,
+                    MsFlags::MS_BIND | MsFlags::MS_REC,
+                    NONE,
+                )?;
+                if *readonly {
+                    remount_readonly(&dst)?;
+                }
+                Ok(())
+            }
+            MountSpec::Tmpfs { dst, options } => {
+                let dst = format!("{}{}", root, dst);
+                mount(
+                    Some("tmpfs"),
+                    dst.as_str(),
+                    Some("tmpfs"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                    options.as_deref(),
+                )
+            }
+        }
+    }
+}
+
+// Attributes to change with `mount_setattr(2)` (Linux 5.12), which our
+// version of the `libc` crate lacks.
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+const MOUNT_ATTR_RDONLY: u64 = 0x0000_0001;
+
+// Make the bind mount on 'path', and all mounts below it, read-only. A bind
+// mount takes the flags of its source, and is only made read-only by
+// changing its attributes, or by remounting it where `mount_setattr(2)` is
+// not available.
+
+fn remount_readonly(path: &str) -> nix::Result<()> {
+    let cpath = CString::new(path).map_err(|_| nix::Error::Sys(Errno::EINVAL))?;
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr as *const MountAttr,
+            mem::size_of::<MountAttr>(),
+        )
+    };
+    match Errno::result(res) {
+        Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            for mount_point in submounts(path)? {
+                remount_one_readonly(&mount_point)?;
+            }
+            Ok(())
+        }
+        res => res.map(drop),
+    }
+}
+
+// Undo the octal escapes of spaces, tabs, newlines and backslashes in a
+// path from `/proc/self/mountinfo`.
+
+fn unescape(path: &str) -> String {
+    let mut out = Vec::with_capacity(path.len());
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match code {
+            Some(byte) if bytes[i] == b'\\' => {
+                out.push(byte);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// The mount points of 'path' and of the mounts below it, parents first.
+
+fn submounts(path: &str) -> nix::Result<Vec<String>> {
+    let path = fs::canonicalize(path).map_err(io_error)?;
+    let mountinfo = fs::read_to_string(MOUNTINFO).map_err(io_error)?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4).map(unescape))
+        .filter(|mount_point| Path::new(mount_point).starts_with(&path))
+        .collect())
+}
+
+// Make the mount on 'path' read-only by remounting it. Inside a user
+// namespace, the flags of mounts inherited from a more privileged mount
+// namespace are locked, and the remount fails unless it keeps them.
+
+fn remount_one_readonly(path: &str) -> nix::Result<()> {
+    const LOCKED: &[(FsFlags, MsFlags)] = &[
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+
+    let current = statvfs(path)?.flags();
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    for &(fs_flag, ms_flag) in LOCKED {
+        if current.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(NONE, path, NONE, flags, NONE)
+}
+
+// Make 'rootfs' the root directory of the calling process, which must be in
+// a new, private mount namespace and a new PID namespace. The old root is
+// detached, so nothing of the host filesystem stays reachable. A new procfs
+// is mounted on `/proc`, showing the PID namespace of the caller, and a
+// tmpfs on `/tmp`. Both `proc` and `tmp` must exist in 'rootfs'. The
+// destinations of 'mounts' are taken to be inside 'rootfs', on top of the
+// new `/tmp`, while their sources are still looked up from the old root.
+
+pub fn enter_rootfs(rootfs: &str, mounts: &[MountSpec]) -> nix::Result<()> {
+    // `pivot_root(2)` needs the new root to be a mount point.
+    mount(
+        Some(rootfs),
+        rootfs,
+        NONE,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        NONE,
+    )?;
+
+    // The default `/tmp` goes first, so that mounts of the caller at or
+    // below `/tmp` end up on top of it rather than hidden under it.
+    let tmp_dir = format!("{}/tmp", rootfs);
+    mount(
+        Some("tmpfs"),
+        tmp_dir.as_str(),
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=1777"),
+    )?;
+
+    for spec in mounts {
+        // Nothing exists in the fresh `/tmp` yet.
+        if spec.dst().starts_with("/tmp/") {
+            spec.create_dst(rootfs)?;
+        }
+        spec.apply(rootfs)?;
+    }
+
+    // The kernel only lets a user namespace mount a procfs while another
+    // one is fully visible, so this has to happen before detaching the old
+    // root.
+    let proc_dir = format!("{}/proc", rootfs);
+    mount(
+        Some("proc"),
+        proc_dir.as_str(),
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        NONE,
+    )?;
+
+    // Pivoting into "." stacks the old root on top of the new one, which
+    // spares us a directory to put the old root in. Detaching the top of
+    // the stack then leaves us with the new root.
+    chdir(rootfs)?;
+    pivot_root(".", ".")?;
+    umount2(".", MntFlags::MNT_DETACH)?;
+    chdir("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_mountinfo_paths() {
+        assert_eq!(unescape("/mnt/a\\040b\\011c"), "/mnt/a b\tc");
+        assert_eq!(unescape("/mnt/back\\134slash"), "/mnt/back\\slash");
+        assert_eq!(unescape("/mnt/trailing\\"), "/mnt/trailing\\");
+    }
+
+    #[test]
+    fn rejects_unsafe_destinations() {
+        assert!(MountSpec::parse("bind", "/srv:/mnt/srv").is_ok());
+        assert!(MountSpec::parse("tmpfs", "/mnt/scratch:size=64m").is_ok());
+        assert!(MountSpec::parse("bind", "/srv:mnt/srv").is_err());
+        assert!(MountSpec::parse("ro-bind", "/srv:/mnt/../../etc").is_err());
+        assert!(MountSpec::parse("bind", "/srv:/..:ro").is_err());
+        assert!(MountSpec::parse("tmpfs", "scratch").is_err());
+        assert!(MountSpec::parse("tmpfs", "/tmp/../etc:size=1m").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use nix::errno::Errno;
+use nix::sched::CloneFlags;
+use nix::sys::stat::{fstat, SFlag};
+use nix::Error;
+use std::fmt;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+// `NS_GET_NSTYPE` (Linux 4.11) returns the `CLONE_NEW*` type of the namespace
+// a namespace file descriptor refers to, and fails with ENOTTY for any other
+// file.
+mod ioctl {
+    nix::ioctl_none!(ns_get_nstype, 0xb7, 0x3);
+}
+
+// Namespace types, named after their entries in `/proc/PID/ns`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NsType {
+    Cgroup,
+    Ipc,
+    Mnt,
+    Net,
+    Pid,
+    Time,
+    User,
+    Uts,
+}
+
+impl NsType {
+    pub const ALL: &'static [NsType] = &[
+        NsType::Cgroup,
+        NsType::Ipc,
+        NsType::Mnt,
+        NsType::Net,
+        NsType::Pid,
+        NsType::Time,
+        NsType::User,
+        NsType::Uts,
+    ];
+
+    pub const VALUES: &'static [&'static str] =
+        &["cgroup", "ipc", "mnt", "net", "pid", "time", "user", "uts"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NsType::Cgroup => "cgroup",
+            NsType::Ipc => "ipc",
+            NsType::Mnt => "mnt",
+            NsType::Net => "net",
+            NsType::Pid => "pid",
+            NsType::Time => "time",
+            NsType::User => "user",
+            NsType::Uts => "uts",
+        }
+    }
+
+    pub fn flag(self) -> libc::c_int {
+        match self {
+            NsType::Cgroup => libc::CLONE_NEWCGROUP,
+            NsType::Ipc => libc::CLONE_NEWIPC,
+            NsType::Mnt => libc::CLONE_NEWNS,
+            NsType::Net => libc::CLONE_NEWNET,
+            NsType::Pid => libc::CLONE_NEWPID,
+            NsType::Time => libc::CLONE_NEWTIME,
+            NsType::User => libc::CLONE_NEWUSER,
+            NsType::Uts => libc::CLONE_NEWUTS,
+        }
+    }
+
+    pub fn from_flag(flag: libc::c_int) -> Option<NsType> {
+        NsType::ALL.iter().find(|t| t.flag() == flag).copied()
+    }
+
+    // The `nstype` argument of `setns()` for this namespace type.
+    pub fn clone_flags(self) -> CloneFlags {
+        // nix does not know about `CLONE_NEWTIME`, and `from_bits()` would
+        // drop it.
+        unsafe { CloneFlags::from_bits_unchecked(self.flag()) }
+    }
+}
+
+impl FromStr for NsType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NsType::ALL
+            .iter()
+            .find(|t| t.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown namespace type: {}", s))
+    }
+}
+
+impl fmt::Display for NsType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Check that 'file' (opened from 'path') is a namespace file, of type
+// 'expected' if given, and return its type. The error message names what was
+// found instead.
+
+pub fn check_ns_file(file: &File, path: &str, expected: Option<NsType>) -> Result<NsType, String> {
+    let found = match unsafe { ioctl::ns_get_nstype(file.as_raw_fd()) } {
+        Ok(flag) => NsType::from_flag(flag)
+            .ok_or_else(|| format!("{}: unknown namespace type {:#x}", path, flag))?,
+        Err(Error::Sys(Errno::ENOTTY)) => {
+            return Err(format!(
+                "{}: not a namespace file, but a {}",
+                path,
+                file_kind(file)
+            ))
+        }
+        Err(err) => return Err(format!("{}: NS_GET_NSTYPE failed: {}", path, err)),
+    };
+
+    match expected {
+        Some(expected) if expected != found => Err(format!(
+            "{}: is a {} namespace, not a {} namespace",
+            path, found, expected
+        )),
+        _ => Ok(found),
+    }
+}
+
+fn file_kind(file: &File) -> &'static str {
+    let mode = match fstat(file.as_raw_fd()) {
+        Ok(st) => SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT,
+        Err(_) => return "file of unknown type",
+    };
+    match mode {
+        SFlag::S_IFREG => "regular file",
+        SFlag::S_IFDIR => "directory",
+        SFlag::S_IFCHR => "character device",
+        SFlag::S_IFBLK => "block device",
+        SFlag::S_IFIFO => "FIFO",
+        SFlag::S_IFSOCK => "socket",
+        SFlag::S_IFLNK => "symbolic link",
+        _ => "file of unknown type",
+    }
+}
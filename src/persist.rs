@@ -0,0 +1,95 @@
+use nix::fcntl::OFlag;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, fork, getppid, pipe2, read, write, ForkResult, Pid};
+use std::fs::{remove_file, OpenOptions};
+use std::os::unix::io::RawFd;
+use std::process;
+
+const NONE: Option<&'static [u8]> = None;
+
+// A namespace stays alive as long as some process is a member of it, or
+// some file descriptor or bind mount refers to it. Bind-mounting one of the
+// `/proc/PID/ns/*` files onto a regular file (as `ip netns add` does) thus
+// lets the namespace outlive the processes in it.
+//
+// The bind mounts have to be created in our original mount namespace, or
+// they would vanish together with a new mount namespace. `Persister` forks
+// a helper process before any namespaces are unshared; the helper waits
+// until it is told that the namespaces exist, and then bind-mounts our
+// namespace files onto the requested paths.
+pub struct Persister {
+    helper: Pid,
+    writer: RawFd,
+}
+
+impl Persister {
+    // Fork the helper. 'targets' holds pairs of namespace file names (as
+    // found in `/proc/PID/ns`) and the paths they should be bound to.
+    pub fn spawn(targets: &[(&str, &str)]) -> Persister {
+        // Close-on-exec, so that the pipe does not leak into the command
+        // run by our caller.
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).expect("pipe2() failed");
+
+        match fork().expect("fork() failed") {
+            ForkResult::Parent { child } => {
+                close(reader).expect("close() failed");
+                Persister {
+                    helper: child,
+                    writer,
+                }
+            }
+            ForkResult::Child => {
+                let parent = getppid();
+                close(writer).expect("close() failed in helper");
+
+                // End of file without data means that our parent gave up.
+                let mut buf: [u8; 1] = [0; 1];
+                if read(reader, &mut buf).expect("read() from pipe failed in helper") == 0 {
+                    process::exit(1);
+                }
+
+                for (ns, path) in targets {
+                    if let Err(err) = bind_ns(parent, ns, path) {
+                        eprintln!("Error persisting {} namespace at {}: {}", ns, path, err);
+                        process::exit(1);
+                    }
+                }
+                process::exit(0);
+            }
+        }
+    }
+
+    // Tell the helper that our namespaces are ready, and wait until it has
+    // bind-mounted them. The process will exit if any of them failed.
+    pub fn persist(self) {
+        write(self.writer, b"x").expect("write() to pipe failed");
+        close(self.writer).expect("close() failed");
+
+        match waitpid(self.helper, None).expect("waitpid() failed") {
+            WaitStatus::Exited(_, 0) => {}
+            _ => process::exit(1),
+        }
+    }
+}
+
+fn bind_ns(pid: Pid, ns: &str, path: &str) -> Result<(), String> {
+    // The mount point has to exist; an empty file will do.
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| err.to_string())?;
+
+    let ns_file = format!("/proc/{}/ns/{}", pid, ns);
+    mount(Some(ns_file.as_str()), path, NONE, MsFlags::MS_BIND, NONE).map_err(|err| err.to_string())
+}
+
+// Undo what `Persister` did: unmount the namespace file bound to 'path',
+// and remove the mount point. The namespace is freed once no process or
+// file descriptor refers to it any more.
+pub fn release(path: &str) -> Result<(), String> {
+    umount2(path, MntFlags::MNT_DETACH).map_err(|err| format!("umount2() failed: {}", err))?;
+    remove_file(path).map_err(|err| format!("unlink() failed: {}", err))
+}
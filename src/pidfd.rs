@@ -0,0 +1,75 @@
+use crate::ns::NsType;
+use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+
+// Obtain a file descriptor referring to process 'pid' (Linux 5.3). Unlike
+// the PID itself, the descriptor keeps referring to the same process even
+// after it has terminated and its PID has been reused.
+
+pub fn pidfd_open(pid: Pid) -> nix::Result<OwnedFd> {
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    Errno::result(res).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+// Join the namespaces of types 'nstypes' of the process referred to by
+// 'pidfd', atomically: either all of them are joined, or none. Kernels
+// before Linux 5.8 fail with EINVAL, since they only accept namespace file
+// descriptors.
+
+pub fn setns_pidfd(pidfd: &OwnedFd, nstypes: &[NsType]) -> nix::Result<()> {
+    let mask = nstypes.iter().fold(0, |mask, t| mask | t.flag());
+    // See `NsType::clone_flags()`.
+    setns(pidfd.as_raw_fd(), unsafe {
+        CloneFlags::from_bits_unchecked(mask)
+    })
+}
+
+// Send 'signal' to the process referred to by 'pidfd' (Linux 5.1), which
+// cannot hit another process that reused its PID.
+
+pub fn pidfd_send_signal(pidfd: &OwnedFd, signal: Signal) -> nix::Result<()> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal as libc::c_int,
+            ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+// Wait for the child referred to by 'pidfd' to terminate (Linux 5.4), and
+// tell how it did.
+
+pub fn wait_pidfd(pidfd: &OwnedFd) -> nix::Result<WaitStatus> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::waitid(
+            libc::P_PIDFD,
+            pidfd.as_raw_fd() as libc::id_t,
+            &mut info,
+            libc::WEXITED,
+        )
+    };
+    Errno::result(res)?;
+
+    let (pid, status) = unsafe { (Pid::from_raw(info.si_pid()), info.si_status()) };
+    match info.si_code {
+        libc::CLD_EXITED => Ok(WaitStatus::Exited(pid, status)),
+        libc::CLD_KILLED | libc::CLD_DUMPED => Ok(WaitStatus::Signaled(
+            pid,
+            Signal::try_from(status)?,
+            info.si_code == libc::CLD_DUMPED,
+        )),
+        _ => Err(nix::Error::Sys(Errno::EINVAL)),
+    }
+}
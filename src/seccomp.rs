@@ -0,0 +1,478 @@
+use crate::syscalls;
+use libc::sock_filter;
+use nix::errno::Errno;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs;
+use std::os::unix::io::RawFd;
+
+// The profile used for `--seccomp default`, modeled on Docker's: anything
+// not explicitly allowed fails with EPERM. `clone3()`, whose flags a filter
+// can't inspect, fails with ENOSYS so that the C library falls back to
+// `clone()`. The README tells how it differs from Docker's.
+const DEFAULT_PROFILE: &str = include_str!("../profiles/seccomp-default.json");
+
+// Architecture of the system calls we resolve names for, as named in
+// profiles and as reported by the kernel in `seccomp_data.arch`.
+#[cfg(target_arch = "x86_64")]
+const ARCH: (&str, u32) = ("x86_64", 0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const ARCH: (&str, u32) = ("aarch64", 0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("seccomp support requires x86_64 or aarch64");
+
+// System calls of the x32 ABI share the AUDIT_ARCH of x86_64, but have this
+// bit set in their numbers.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets of the fields of `struct seccomp_data`. The six arguments are
+// 64-bit values, whose low half comes first on the (little-endian)
+// architectures we support.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+const MAX_ARGS: u32 = 6;
+
+// Action handing a system call to a supervisor through a listener fd (not
+// in our version of the `libc` crate yet).
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+
+// Maximum number of instructions in a BPF program.
+const BPF_MAXINSNS: usize = 4096;
+
+// What to do with a system call.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Allow,
+    Errno,
+    Kill,
+    Log,
+    Trap,
+}
+
+// How an argument of a system call is compared with a value.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Op {
+    Eq,
+    Ne,
+    MaskedEq,
+}
+
+// A condition on argument 'index' (from 0) of a system call: it is equal
+// to 'value', not equal to it, or equal to it after being ANDed with
+// 'mask'.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ArgCondition {
+    pub index: u32,
+    pub op: Op,
+    pub value: u64,
+    #[serde(default)]
+    pub mask: Option<u64>,
+}
+
+// Action for the system calls in 'names', on the architectures in 'arches'
+// (all of them if empty), if all conditions in 'args' hold. With action
+// `errno`, the system calls fail with 'errno' (EPERM by default).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub names: Vec<String>,
+    pub action: Action,
+    #[serde(default)]
+    pub errno: Option<u32>,
+    #[serde(default)]
+    pub arches: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<ArgCondition>,
+}
+
+// A seccomp profile, read from a JSON or TOML file. The first rule naming a
+// system call whose conditions hold decides about it; system calls no rule
+// decides about get 'default_action'.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub default_action: Action,
+    #[serde(default)]
+    pub default_errno: Option<u32>,
+    #[serde(default)]
+    pub syscalls: Vec<Rule>,
+}
+
+fn ret_value(action: Action, errno: Option<u32>) -> u32 {
+    match action {
+        Action::Allow => libc::SECCOMP_RET_ALLOW,
+        Action::Errno => {
+            let errno = errno.unwrap_or(libc::EPERM as u32);
+            libc::SECCOMP_RET_ERRNO | (errno & libc::SECCOMP_RET_DATA)
+        }
+        Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        Action::Log => libc::SECCOMP_RET_LOG,
+        Action::Trap => libc::SECCOMP_RET_TRAP,
+    }
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+impl ArgCondition {
+    // Instructions checking this condition on the system call in the
+    // accumulator, which jump 'fail' instructions past their end if it
+    // does not hold, and fall through otherwise.
+
+    fn check(&self, fail: usize) -> Result<Vec<sock_filter>, String> {
+        if self.index >= MAX_ARGS {
+            return Err(format!("no argument {} to compare", self.index));
+        }
+        let (mask, op) = match (self.op, self.mask) {
+            (Op::MaskedEq, Some(mask)) => (Some(mask), Op::Eq),
+            (Op::MaskedEq, None) => return Err("masked-eq needs a mask".to_string()),
+            (op, None) => (None, op),
+            (_, Some(_)) => return Err("only masked-eq takes a mask".to_string()),
+        };
+        let offset = ARGS_OFFSET + 8 * self.index;
+        let jump_offset =
+            |n: usize| u8::try_from(n).map_err(|_| "too many argument conditions".to_string());
+        let load = |half: u32| stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset + half);
+        let and = |mask: u64| stmt(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, mask as u32);
+        let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let (high, low) = ((self.value >> 32) as u32, self.value as u32);
+
+        let prog = match op {
+            // Both halves have to be equal.
+            Op::Eq => {
+                let masked = mask.is_some() as usize;
+                let mut prog = vec![load(4)];
+                prog.extend(mask.map(|mask| and(mask >> 32)));
+                prog.push(jump(jeq, high, 0, jump_offset(2 + masked + fail)?));
+                prog.push(load(0));
+                prog.extend(mask.map(and));
+                prog.push(jump(jeq, low, 0, jump_offset(fail)?));
+                prog
+            }
+            // Either half has to differ.
+            _ => vec![
+                load(4),
+                jump(jeq, high, 0, 2),
+                load(0),
+                jump(jeq, low, jump_offset(fail)?, 0),
+            ],
+        };
+        Ok(prog)
+    }
+}
+
+impl Profile {
+    // Read the profile in 'path', or the built-in one if 'path' is
+    // `default`. Files named `*.toml` are read as TOML, others as JSON.
+
+    pub fn load(path: &str) -> Result<Profile, String> {
+        if path == "default" {
+            return Profile::from_json(DEFAULT_PROFILE);
+        }
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        if path.ends_with(".toml") {
+            Profile::from_toml(&text)
+        } else {
+            Profile::from_json(&text)
+        }
+        .map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn from_json(text: &str) -> Result<Profile, String> {
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn from_toml(text: &str) -> Result<Profile, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    // Compile this profile into a BPF program for the architecture we are
+    // built for. System calls of other architectures (including 32-bit
+    // ones on 64-bit systems) kill the process. Names of system calls
+    // which only exist on other architectures are skipped, since profiles
+    // are shared between architectures, but names unknown everywhere are
+    // an error, lest a misspelt system call escape its rule.
+
+    pub fn compile(&self) -> Result<Vec<sock_filter>, String> {
+        let (arch_name, audit_arch) = ARCH;
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+
+        let mut prog = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                audit_arch,
+                1,
+                0,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, kill),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        prog.extend_from_slice(&[
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, kill),
+        ]);
+
+        let mut seen = HashSet::new();
+        for rule in &self.syscalls {
+            if !rule.arches.is_empty() && !rule.arches.iter().any(|a| a == arch_name) {
+                continue;
+            }
+            let ret = ret_value(rule.action, rule.errno);
+            for name in &rule.names {
+                let nr = match syscalls::lookup(name) {
+                    Some(nr) => nr,
+                    None if syscalls::is_known(name) => continue,
+                    None => return Err(format!("unknown system call: {}", name)),
+                };
+                if seen.contains(&nr) {
+                    continue;
+                }
+                if rule.args.is_empty() {
+                    seen.insert(nr);
+                    prog.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr, 0, 1));
+                    prog.push(stmt(libc::BPF_RET | libc::BPF_K, ret));
+                    continue;
+                }
+
+                // The checks load the arguments into the accumulator, so
+                // where they fail, the number has to be loaded again for
+                // the following rules.
+                let mut checks = Vec::new();
+                for arg in rule.args.iter().rev() {
+                    let fail = checks.len() + 1;
+                    let check = arg
+                        .check(fail)
+                        .map_err(|err| format!("{}: {}", name, err))?;
+                    checks.splice(0..0, check);
+                }
+                let skip = u8::try_from(checks.len() + 2)
+                    .map_err(|_| format!("{}: too many argument conditions", name))?;
+                prog.push(jump(
+                    libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                    nr,
+                    0,
+                    skip,
+                ));
+                prog.extend(checks);
+                prog.push(stmt(libc::BPF_RET | libc::BPF_K, ret));
+                prog.push(stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET));
+            }
+        }
+        prog.push(stmt(
+            libc::BPF_RET | libc::BPF_K,
+            ret_value(self.default_action, self.default_errno),
+        ));
+
+        if prog.len() > BPF_MAXINSNS {
+            return Err(format!(
+                "profile compiles to {} BPF instructions, more than the {} allowed",
+                prog.len(),
+                BPF_MAXINSNS
+            ));
+        }
+        Ok(prog)
+    }
+}
+
+// A BPF program handing the system calls in 'names' to a supervisor, and
+// allowing all others (which other filters may still restrict). System
+// calls of other architectures are left alone, too.
+
+pub fn notify_filter(names: &[&str]) -> Vec<sock_filter> {
+    let (_, audit_arch) = ARCH;
+    let allow = libc::SECCOMP_RET_ALLOW;
+
+    let mut prog = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            audit_arch,
+            1,
+            0,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, allow),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+    ];
+    for nr in names.iter().filter_map(|name| syscalls::lookup(name)) {
+        prog.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr, 0, 1));
+        prog.push(stmt(libc::BPF_RET | libc::BPF_K, SECCOMP_RET_USER_NOTIF));
+    }
+    prog.push(stmt(libc::BPF_RET | libc::BPF_K, allow));
+    prog
+}
+
+fn set_filter(prog: &[sock_filter], flags: libc::c_ulong) -> nix::Result<libc::c_long> {
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(res)?;
+
+    let fprog = libc::sock_fprog {
+        len: prog.len() as u16,
+        filter: prog.as_ptr() as *mut sock_filter,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &fprog as *const libc::sock_fprog,
+        )
+    };
+    Errno::result(res)
+}
+
+// Install the seccomp filter 'prog' for the calling thread. Setting
+// `no_new_privs` first lets us do so without CAP_SYS_ADMIN, and keeps
+// set-user-ID programs from running under a filter they don't expect.
+
+pub fn install(prog: &[sock_filter]) -> nix::Result<()> {
+    set_filter(prog, 0).map(drop)
+}
+
+// Install the seccomp filter 'prog', which hands system calls to a
+// supervisor, and return the listener fd the supervisor receives them on.
+
+pub fn install_listener(prog: &[sock_filter]) -> nix::Result<RawFd> {
+    set_filter(prog, libc::SECCOMP_FILTER_FLAG_NEW_LISTENER).map(|fd| fd as RawFd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_default_profile() {
+        let prog = Profile::load("default").unwrap().compile().unwrap();
+        let last = prog.last().unwrap();
+        assert_eq!(last.k, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    }
+
+    #[test]
+    fn reads_json_and_toml_alike() {
+        let json = r#"{
+            "default_action": "allow",
+            "syscalls": [{ "names": ["keyctl", "bpf"], "action": "errno", "errno": 38 }]
+        }"#;
+        let toml = r#"
+            default_action = "allow"
+
+            [[syscalls]]
+            names = ["keyctl", "bpf"]
+            action = "errno"
+            errno = 38
+        "#;
+        assert_eq!(
+            Profile::from_json(json).unwrap(),
+            Profile::from_toml(toml).unwrap()
+        );
+        assert!(Profile::from_json(r#"{ "default_action": "deny" }"#).is_err());
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let profile = Profile::from_json(
+            r#"{
+                "default_action": "allow",
+                "syscalls": [
+                    { "names": ["getpid"], "action": "kill" },
+                    { "names": ["getpid"], "action": "allow" },
+                    { "names": ["getppid"], "action": "log", "arches": ["mips"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let prog = profile.compile().unwrap();
+        let nr = syscalls::lookup("getpid").unwrap();
+        let checks: Vec<_> = prog
+            .windows(2)
+            .filter(|w| w[0].code as u32 == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K)
+            .filter(|w| w[0].k == nr)
+            .map(|w| w[1].k)
+            .collect();
+        assert_eq!(checks, vec![libc::SECCOMP_RET_KILL_PROCESS]);
+        assert!(!prog.iter().any(|insn| insn.k == libc::SECCOMP_RET_LOG));
+    }
+
+    #[test]
+    fn rejects_unknown_syscalls() {
+        let profile = Profile::from_json(
+            r#"{
+                "default_action": "allow",
+                "syscalls": [{ "names": ["getpid", "gettpid"], "action": "kill" }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            profile.compile().unwrap_err(),
+            "unknown system call: gettpid"
+        );
+    }
+
+    #[test]
+    fn checks_argument_conditions() {
+        let compile = |args: &str| {
+            Profile::from_json(&format!(
+                r#"{{
+                    "default_action": "allow",
+                    "syscalls": [{{ "names": ["personality"], "action": "kill", "args": {} }}]
+                }}"#,
+                args
+            ))
+            .unwrap()
+            .compile()
+        };
+        let prog = compile(r#"[{ "index": 0, "op": "eq", "value": 4294967295 }]"#).unwrap();
+        let nr = syscalls::lookup("personality").unwrap();
+        let start = prog.iter().position(|insn| insn.k == nr).unwrap();
+        // The number check skips the argument checks, the action and the
+        // reload of the number.
+        assert_eq!(start + 1 + prog[start].jf as usize, prog.len() - 1);
+        assert_eq!(prog[start + 2].k, 0);
+        assert_eq!(prog[start + 4].k, u32::MAX);
+        assert_eq!(prog[prog.len() - 3].k, libc::SECCOMP_RET_KILL_PROCESS);
+
+        assert!(compile(r#"[{ "index": 6, "op": "eq", "value": 0 }]"#).is_err());
+        assert!(compile(r#"[{ "index": 0, "op": "masked-eq", "value": 0 }]"#).is_err());
+        assert!(compile(r#"[{ "index": 0, "op": "ne", "value": 0, "mask": 1 }]"#).is_err());
+    }
+
+    #[test]
+    fn notifies_listed_syscalls_only() {
+        let prog = notify_filter(&["mknodat", "no_such_syscall"]);
+        let nr = syscalls::lookup("mknodat").unwrap();
+        let notified: Vec<_> = prog
+            .windows(2)
+            .filter(|w| w[1].k == SECCOMP_RET_USER_NOTIF)
+            .map(|w| w[0].k)
+            .collect();
+        assert_eq!(notified, vec![nr]);
+        assert_eq!(prog.last().unwrap().k, libc::SECCOMP_RET_ALLOW);
+    }
+}
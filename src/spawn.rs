@@ -0,0 +1,137 @@
+use crate::cgroup::{self, Cgroup};
+use crate::pidfd::{pidfd_send_signal, wait_pidfd};
+use nix::errno::Errno;
+use nix::sched::{clone, CloneFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use std::mem;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+
+// Flag of `clone3()` to create the child in a given cgroup, which does not
+// fit in the `c_int` the `libc` crate declares it as.
+const CLONE_INTO_CGROUP: u64 = 0x2_0000_0000;
+
+// A child created by `spawn()`.
+pub struct Child {
+    pub pid: Pid,
+    // A file descriptor referring to the child, if it was created by
+    // `clone3()`.
+    pub pidfd: Option<OwnedFd>,
+    // Whether the child was created in the cgroup passed to `spawn()`.
+    pub in_cgroup: bool,
+}
+
+impl Child {
+    // The system call the child was created by, for verbose output.
+
+    pub fn created_by(&self) -> &'static str {
+        if self.pidfd.is_some() {
+            "clone3"
+        } else {
+            "clone"
+        }
+    }
+
+    // Send 'signal' to the child.
+
+    pub fn signal(&self, signal: Signal) -> nix::Result<()> {
+        match &self.pidfd {
+            Some(pidfd) => pidfd_send_signal(pidfd, signal),
+            None => kill(self.pid, signal),
+        }
+    }
+
+    // Wait for the child to terminate. Waiting on a pidfd needs Linux 5.4,
+    // one more than creating it does.
+
+    pub fn wait(&self) -> nix::Result<WaitStatus> {
+        match self.pidfd.as_ref().map(wait_pidfd) {
+            Some(Err(nix::Error::Sys(Errno::EINVAL))) | None => waitpid(self.pid, None),
+            Some(res) => res,
+        }
+    }
+
+    // Kill and reap the child after we failed to set it up, remove its
+    // cgroup, and exit.
+
+    pub fn abort(&self, cgroup: Option<Cgroup>) -> ! {
+        self.signal(Signal::SIGKILL).expect("kill() failed");
+        self.wait().expect("waitpid() failed");
+        cgroup::discard(cgroup)
+    }
+}
+
+// Create a child with 'flags', which runs 'cb' and exits with the value it
+// returns, and which sends SIGCHLD when it terminates.
+//
+// The child is created by `clone3()` (Linux 5.3), which returns a pidfd for
+// it, and with 'cgroup', the file descriptor of a cgroup directory, places
+// it in that cgroup from the start (Linux 5.7). Without CLONE_VM, the child
+// runs on a copy of our stack, as after `fork()`. Where `clone3()` rejects
+// CLONE_INTO_CGROUP, the child is created outside the cgroup, and where it
+// is not available at all, the child is created by `clone()` instead,
+// running on 'stack'. Either way, it is up to the caller to move the child
+// into the cgroup.
+
+pub fn spawn<'a>(
+    mut cb: impl FnMut() -> isize + 'a,
+    stack: &mut [u8],
+    flags: CloneFlags,
+    cgroup: Option<RawFd>,
+) -> nix::Result<Child> {
+    let mut res = clone3(&mut cb, flags, cgroup);
+    if cgroup.is_some() {
+        if let Err(nix::Error::Sys(Errno::EINVAL)) | Err(nix::Error::Sys(Errno::E2BIG)) = res {
+            res = clone3(&mut cb, flags, None);
+        }
+    }
+    match res {
+        Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            let pid = clone(Box::new(cb), stack, flags, Some(Signal::SIGCHLD as i32))?;
+            Ok(Child {
+                pid,
+                pidfd: None,
+                in_cgroup: false,
+            })
+        }
+        res => res,
+    }
+}
+
+// Create a child with `clone3()`, in 'cgroup' if given, as for `spawn()`.
+
+fn clone3(
+    cb: &mut impl FnMut() -> isize,
+    flags: CloneFlags,
+    cgroup: Option<RawFd>,
+) -> nix::Result<Child> {
+    let mut pidfd: RawFd = -1;
+    let mut args: libc::clone_args = unsafe { mem::zeroed() };
+    args.flags = flags.bits() as u64 | libc::CLONE_PIDFD as u64;
+    args.pidfd = &mut pidfd as *mut RawFd as u64;
+    args.exit_signal = libc::SIGCHLD as u64;
+    if let Some(fd) = cgroup {
+        args.flags |= CLONE_INTO_CGROUP;
+        args.cgroup = fd as u64;
+    }
+
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &mut args as *mut libc::clone_args,
+            mem::size_of::<libc::clone_args>(),
+        )
+    };
+    match Errno::result(res)? {
+        0 => {
+            let code = cb();
+            unsafe { libc::_exit(code as libc::c_int) }
+        }
+        pid => Ok(Child {
+            pid: Pid::from_raw(pid as libc::pid_t),
+            pidfd: Some(unsafe { OwnedFd::from_raw_fd(pidfd) }),
+            in_cgroup: cgroup.is_some(),
+        }),
+    }
+}
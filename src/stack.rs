@@ -0,0 +1,155 @@
+use clap::{Arg, ArgMatches};
+use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
+use nix::unistd::{sysconf, SysconfVar};
+use std::ffi::c_void;
+use std::ptr;
+use std::slice;
+
+// Default size of the stack of a cloned child.
+pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+
+// Smallest stack size we accept for a cloned child.
+pub const MIN_STACK_SIZE: usize = 64 * 1024;
+
+// Largest stack size we accept for a cloned child.
+pub const MAX_STACK_SIZE: usize = 1024 * 1024 * 1024;
+
+fn page_size() -> usize {
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) => size as usize,
+        _ => 4096,
+    }
+}
+
+// Parse a stack size given in bytes, optionally with a `K`, `M` or `G`
+// suffix (powers of 1024).
+
+pub fn parse_stack_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("'{}' is not a size", s))?;
+    if size < MIN_STACK_SIZE {
+        return Err(format!("must be at least {}K", MIN_STACK_SIZE >> 10));
+    }
+    if size > MAX_STACK_SIZE {
+        return Err(format!("must be at most {}G", MAX_STACK_SIZE >> 30));
+    }
+    Ok(size)
+}
+
+// The `--stack-size` option of the programs cloning children. A child
+// created with `clone3()` runs on a copy of the caller's stack instead, so
+// the option only matters where that falls back to `clone()`.
+
+pub fn arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("stack-size")
+        .help("size of the child's stack if created with clone() rather than clone3(), in bytes or with a K, M or G suffix [default: 1M]")
+        .long("stack-size")
+        .takes_value(true)
+        .value_name("SIZE")
+}
+
+// The stack size given with `--stack-size`, or DEFAULT_STACK_SIZE. Exits
+// with a usage error if it is invalid.
+
+pub fn from_matches(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("stack-size")
+        .map_or(DEFAULT_STACK_SIZE, |s| {
+            parse_stack_size(s).unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid --stack-size: {}", err),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        })
+}
+
+// The stack of a cloned child. It is mapped apart from the caller's own
+// stack, with an inaccessible guard page below it, so that a child which
+// overflows its stack is killed by SIGSEGV rather than writing over memory
+// of the caller.
+pub struct ChildStack {
+    base: *mut c_void,
+    len: usize,
+    guard: usize,
+}
+
+impl ChildStack {
+    // Map a stack of at least 'size' bytes, plus its guard page.
+
+    pub fn new(size: usize) -> ChildStack {
+        let guard = page_size();
+        let len = size
+            .div_ceil(guard)
+            .checked_mul(guard)
+            .and_then(|len| len.checked_add(guard))
+            .unwrap_or_else(|| panic!("Child stack of {} bytes is too large", size));
+        let base = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_STACK,
+                -1,
+                0,
+            )
+        }
+        .unwrap_or_else(|err| panic!("Error mapping child stack of {} bytes: {}", len, err));
+
+        // Stacks grow downwards, so the guard page goes at the lowest
+        // address.
+        unsafe { mprotect(base, guard, ProtFlags::PROT_NONE) }
+            .unwrap_or_else(|err| panic!("Error protecting child stack guard page: {}", err));
+
+        ChildStack { base, len, guard }
+    }
+
+    // The usable part of the stack, to be passed to `clone()`.
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                (self.base as *mut u8).add(self.guard),
+                self.len - self.guard,
+            )
+        }
+    }
+}
+
+impl Drop for ChildStack {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.base, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stack_sizes() {
+        assert_eq!(parse_stack_size("65536"), Ok(MIN_STACK_SIZE));
+        assert_eq!(parse_stack_size("256K"), Ok(256 << 10));
+        assert_eq!(parse_stack_size("2m"), Ok(2 << 20));
+        assert_eq!(parse_stack_size("1G"), Ok(1 << 30));
+        assert!(parse_stack_size("64").is_err());
+        assert!(parse_stack_size("63K").is_err());
+        assert!(parse_stack_size("1T").is_err());
+        assert!(parse_stack_size("2G").is_err());
+        assert!(parse_stack_size(&format!("{}", MAX_STACK_SIZE + 1)).is_err());
+        assert!(parse_stack_size(&format!("{}", usize::MAX)).is_err());
+        assert!(parse_stack_size("").is_err());
+        assert!(parse_stack_size(&format!("{}G", usize::MAX >> 20)).is_err());
+        assert!(parse_stack_size(&format!("{}0", usize::MAX)).is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use nix::sys::wait::WaitStatus;
+
+// The exit status with which to pass on the termination of a child: its own
+// exit status, or 128 plus the signal number if it was killed by a signal
+// (the convention followed by shells).
+
+pub fn exit_code(status: WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 1,
+    }
+}
+
+// How a child terminated, for verbose output.
+
+pub fn describe(status: WaitStatus) -> String {
+    match status {
+        WaitStatus::Exited(_, code) => format!("exited with status {}", code),
+        WaitStatus::Signaled(_, signal, true) => {
+            format!("killed by signal {} (core dumped)", signal)
+        }
+        WaitStatus::Signaled(_, signal, false) => format!("killed by signal {}", signal),
+        status => format!("changed state: {:?}", status),
+    }
+}
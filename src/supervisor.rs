@@ -0,0 +1,310 @@
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, MsFlags};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags,
+    SockFlag, SockType,
+};
+use nix::sys::stat::{stat, umask, Mode};
+use nix::sys::uio::IoVec;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{chroot, close, fchdir, fork, unlink, ForkResult};
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+
+// System calls handed to the supervisor.
+pub const SUPERVISED: &[&str] = &["mknod", "mknodat"];
+
+// Character devices a supervised child may create, as bind mounts of the
+// device files of the same name in its `/dev`.
+const DEVICES: &[(&str, u32, u32)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+// Let the kernel carry out the system call as if it had not been
+// intercepted.
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+
+nix::ioctl_readwrite!(notif_recv, b'!', 0, libc::seccomp_notif);
+nix::ioctl_readwrite!(notif_send, b'!', 1, libc::seccomp_notif_resp);
+nix::ioctl_write_ptr!(notif_id_valid, b'!', 2, u64);
+
+// A connected pair of sockets, through which a child passes its seccomp
+// listener to the parent.
+
+pub fn listener_socket() -> nix::Result<(RawFd, RawFd)> {
+    socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )
+}
+
+// Send file descriptor 'fd' over socket 'sock'.
+
+pub fn send_fd(sock: RawFd, fd: RawFd) -> nix::Result<()> {
+    let iov = [IoVec::from_slice(b"x")];
+    let fds = [fd];
+    sendmsg(
+        sock,
+        &iov,
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .map(drop)
+}
+
+// Receive a file descriptor sent over socket 'sock' with `send_fd()`, or
+// `None` if the other end was closed without sending one.
+
+pub fn recv_fd(sock: RawFd) -> nix::Result<Option<RawFd>> {
+    let mut buf = [0; 1];
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let msg = recvmsg(sock, &iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC)?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            return Ok(fds.first().cloned());
+        }
+    }
+    Ok(None)
+}
+
+// Handle the system calls notified through seccomp 'listener' until no
+// process uses its filter any longer.
+//
+// `mknod()` of one of the character devices in DEVICES is emulated by
+// creating an empty file and bind-mounting the device from the caller's
+// `/dev` onto it, which works even where the caller may not create device
+// nodes, such as in a user namespace. Other file types are left to the
+// kernel, and other devices fail with EPERM.
+
+pub fn supervise(listener: RawFd, verbose: bool) {
+    loop {
+        let mut fds = [PollFd::new(listener, PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => panic!("poll() on seccomp listener failed: {}", err),
+        }
+        let revents = fds[0].revents().unwrap_or_else(PollFlags::empty);
+        if !revents.contains(PollFlags::POLLIN) {
+            break;
+        }
+
+        let mut req: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+        match unsafe { notif_recv(listener, &mut req) } {
+            Ok(_) => {}
+            // The caller was killed before we got to its notification.
+            Err(nix::Error::Sys(Errno::ENOENT)) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => panic!("Error receiving seccomp notification: {}", err),
+        }
+
+        let mut resp = libc::seccomp_notif_resp {
+            id: req.id,
+            val: 0,
+            error: 0,
+            flags: 0,
+        };
+        match handle_mknod(listener, &req) {
+            Ok(Some(call)) => {
+                if verbose {
+                    println!("supervisor: emulated {} for PID {}", call, req.pid);
+                }
+            }
+            Ok(None) => resp.flags = SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+            Err(errno) => resp.error = -(errno as i32),
+        }
+        // Fails with ENOENT if the caller has gone in the meantime.
+        let _ = unsafe { notif_send(listener, &mut resp) };
+    }
+    let _ = close(listener);
+}
+
+// Handle a notified `mknod()` or `mknodat()`. Returns a description of the
+// emulated call, `None` if the kernel should carry it out, or the error
+// with which it should fail.
+
+fn handle_mknod(listener: RawFd, req: &libc::seccomp_notif) -> Result<Option<String>, Errno> {
+    let args = &req.data.args;
+    let (dirfd, path, mode, dev) = if req.data.nr as libc::c_long == libc::SYS_mknodat {
+        (args[0] as i32, args[1], args[2] as u32, args[3])
+    } else {
+        (libc::AT_FDCWD, args[0], args[1] as u32, args[2])
+    };
+    if mode & libc::S_IFMT != libc::S_IFCHR {
+        return Ok(None);
+    }
+
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+    let device = DEVICES
+        .iter()
+        .find(|&&(_, ma, mi)| (ma, mi) == (major, minor))
+        .map(|&(name, _, _)| name)
+        .ok_or(Errno::EPERM)?;
+
+    let pid = req.pid;
+    let path = read_path(pid, path)?;
+    // The caller may have been killed, and its PID reused, while we read
+    // its memory.
+    let id = req.id;
+    if unsafe { notif_id_valid(listener, &id) }.is_err() {
+        return Err(Errno::ENOENT);
+    }
+
+    let base = if path.as_bytes().starts_with(b"/") {
+        None
+    } else if dirfd == libc::AT_FDCWD {
+        Some(format!("/proc/{}/cwd", pid))
+    } else {
+        Some(format!("/proc/{}/fd/{}", pid, dirfd))
+    };
+    emulate(pid, &path, base.as_deref(), mode, device)?;
+    Ok(Some(format!(
+        "mknod({:?}, char {}:{}) as bind mount of /dev/{}",
+        path, major, minor, device
+    )))
+}
+
+// Read the NUL-terminated path at 'addr' in the memory of process 'pid'.
+
+fn read_path(pid: u32, addr: u64) -> Result<CString, Errno> {
+    let mem = File::open(format!("/proc/{}/mem", pid)).map_err(io_errno)?;
+    let mut buf = vec![0; libc::PATH_MAX as usize];
+    let len = mem.read_at(&mut buf, addr).map_err(|_| Errno::EFAULT)?;
+    let len = buf[..len]
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Errno::ENAMETOOLONG)?;
+    buf.truncate(len);
+    CString::new(buf).map_err(|_| Errno::EINVAL)
+}
+
+fn io_errno(err: std::io::Error) -> Errno {
+    Errno::from_i32(err.raw_os_error().unwrap_or(libc::EIO))
+}
+
+fn open_ro(path: &str) -> Result<RawFd, Errno> {
+    open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()).map_err(nix_errno)
+}
+
+fn nix_errno(err: nix::Error) -> Errno {
+    err.as_errno().unwrap_or(Errno::EINVAL)
+}
+
+// The umask of process 'pid', as shown in its status file.
+
+fn read_umask(pid: u32) -> Mode {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .and_then(|s| libc::mode_t::from_str_radix(s.trim(), 8).ok())
+        .map(Mode::from_bits_truncate)
+        .unwrap_or_else(|| Mode::from_bits_truncate(0o022))
+}
+
+// Create 'path' as a bind mount of `/dev/<device>` with the root directory,
+// namespaces and umask of process 'pid'. Relative paths are looked up from
+// 'base'.
+//
+// Joining the namespaces of the process requires a process of our own, so
+// this is done by a child, which exits with the errno value of the first
+// step that failed.
+
+fn emulate(
+    pid: u32,
+    path: &CStr,
+    base: Option<&str>,
+    mode: u32,
+    device: &str,
+) -> Result<(), Errno> {
+    let root = open_ro(&format!("/proc/{}/root", pid))?;
+    let base = base.map(open_ro).transpose()?;
+    let user_ns = format!("/proc/{}/ns/user", pid);
+    // Joining our own user namespace fails, and is not needed either.
+    let user_ns = if stat(user_ns.as_str()).ok().map(|st| st.st_ino)
+        == stat("/proc/self/ns/user").ok().map(|st| st.st_ino)
+    {
+        None
+    } else {
+        Some(open_ro(&user_ns)?)
+    };
+    let mnt_ns = open_ro(&format!("/proc/{}/ns/mnt", pid))?;
+    let perm = Mode::from_bits_truncate(mode & 0o7777) & !read_umask(pid);
+    let src = CString::new(format!("/dev/{}", device)).unwrap();
+
+    let result = match fork() {
+        Ok(ForkResult::Child) => {
+            let code = match bind_device(root, base, user_ns, mnt_ns, &src, path, perm) {
+                Ok(()) => 0,
+                Err(errno) => errno as i32,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+            Ok(WaitStatus::Exited(_, code)) => Err(Errno::from_i32(code)),
+            _ => Err(Errno::EIO),
+        },
+        Err(err) => Err(nix_errno(err)),
+    };
+    for fd in vec![root, mnt_ns].into_iter().chain(base).chain(user_ns) {
+        let _ = close(fd);
+    }
+    result
+}
+
+// In the child forked by `emulate()`.
+
+fn bind_device(
+    root: RawFd,
+    base: Option<RawFd>,
+    user_ns: Option<RawFd>,
+    mnt_ns: RawFd,
+    src: &CStr,
+    path: &CStr,
+    perm: Mode,
+) -> Result<(), Errno> {
+    if let Some(user_ns) = user_ns {
+        setns(user_ns, CloneFlags::CLONE_NEWUSER).map_err(nix_errno)?;
+    }
+    setns(mnt_ns, CloneFlags::CLONE_NEWNS).map_err(nix_errno)?;
+    fchdir(root).map_err(nix_errno)?;
+    chroot(".").map_err(nix_errno)?;
+    if let Some(base) = base {
+        fchdir(base).map_err(nix_errno)?;
+    }
+
+    umask(Mode::empty());
+    let fd = open(
+        path,
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_CLOEXEC,
+        perm,
+    )
+    .map_err(nix_errno)?;
+    let _ = close(fd);
+
+    mount(
+        Some(src),
+        path,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .map_err(|err| {
+        let _ = unlink(path);
+        nix_errno(err)
+    })
+}
@@ -0,0 +1,96 @@
+use clap::{value_t, Arg, ArgMatches};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::fs;
+
+const TIMENS_OFFSETS: &str = "/proc/self/timens_offsets";
+const TIME_FOR_CHILDREN: &str = "/proc/self/ns/time_for_children";
+
+// The `--monotonic` and `--boottime` options.
+
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("monotonic")
+            .help("CLOCK_MONOTONIC offset in the new time namespace; implies --time")
+            .long("monotonic")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .value_name("OFFSET"),
+        Arg::with_name("boottime")
+            .help("CLOCK_BOOTTIME offset in the new time namespace; implies --time")
+            .long("boottime")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .value_name("OFFSET"),
+    ]
+}
+
+// The offsets of CLOCK_MONOTONIC and CLOCK_BOOTTIME given with the options
+// of `args()`, if any. Exits with a usage error if either is invalid.
+
+pub fn from_matches(matches: &ArgMatches) -> (Option<i64>, Option<i64>) {
+    let offset = |arg: &str| {
+        matches
+            .value_of(arg)
+            .map(|_| value_t!(matches, arg, i64).unwrap_or_else(|e| e.exit()))
+    };
+    (offset("monotonic"), offset("boottime"))
+}
+
+// The contents of `timens_offsets` for the given offsets in seconds.
+
+fn offsets(monotonic: Option<i64>, boottime: Option<i64>) -> String {
+    let mut offsets = String::new();
+    if let Some(secs) = monotonic {
+        offsets.push_str(&format!("monotonic {} 0\n", secs));
+    }
+    if let Some(secs) = boottime {
+        offsets.push_str(&format!("boottime {} 0\n", secs));
+    }
+    offsets
+}
+
+// Create a new time namespace for the children of the calling process, and
+// shift its CLOCK_MONOTONIC and CLOCK_BOOTTIME clocks by 'monotonic' and
+// 'boottime' seconds.
+//
+// `CLONE_NEWTIME` shares its bit with the termination signal argument of
+// `clone(2)`, so it can only be requested through `unshare(2)` (or
+// `clone3(2)`). The calling process itself stays in its time namespace;
+// the offsets have to be written before any process enters the new one,
+// that is, before the first child is created.
+
+pub fn unshare_time(monotonic: Option<i64>, boottime: Option<i64>) -> nix::Result<()> {
+    let res = unsafe { libc::unshare(libc::CLONE_NEWTIME) };
+    Errno::result(res)?;
+
+    let offsets = offsets(monotonic, boottime);
+    if offsets.is_empty() {
+        return Ok(());
+    }
+    fs::write(TIMENS_OFFSETS, offsets)
+        .map_err(|err| nix::Error::Sys(Errno::from_i32(err.raw_os_error().unwrap_or(libc::EIO))))
+}
+
+// Create a new time namespace with the given offsets, like `unshare_time()`,
+// and move the calling process itself into it.
+//
+// This is for a cloned child that has just got a user namespace of its
+// own, so that the time namespace is owned by that user namespace rather
+// than by ours, where an unprivileged caller could not create it. Joining
+// a time namespace requires a single-threaded process.
+
+pub fn enter_new_time(monotonic: Option<i64>, boottime: Option<i64>) -> nix::Result<()> {
+    unshare_time(monotonic, boottime)?;
+
+    let fd = open(
+        TIME_FOR_CHILDREN,
+        OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let res = unsafe { libc::setns(fd, libc::CLONE_NEWTIME) };
+    close(fd)?;
+    Errno::result(res).map(drop)
+}
@@ -0,0 +1,550 @@
+use nix::unistd::Pid;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use std::str::FromStr;
+
+// Maximum number of records in a UID or GID map (since Linux 4.15).
+pub const MAX_ID_MAP_ENTRIES: usize = 340;
+
+// Number of subordinate IDs mapped by `subid_map()`, as is customary for
+// rootless containers.
+pub const SUBID_MAP_LENGTH: u32 = 65536;
+
+// One record of a UID or GID map: 'length' consecutive IDs starting at
+// 'inside' in the user namespace map to those starting at 'outside' in
+// the parent user namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMapEntry {
+    pub inside: u32,
+    pub outside: u32,
+    pub length: u32,
+}
+
+impl fmt::Display for IdMapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.inside, self.outside, self.length)
+    }
+}
+
+// A UID or GID map, validated the way the kernel would validate it when it
+// is written to `/proc/PID/uid_map` or `/proc/PID/gid_map`, so that mistakes
+// are reported before they turn into a bare EINVAL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMap {
+    entries: Vec<IdMapEntry>,
+}
+
+// Why a UID or GID map was rejected. 'entry' is the 1-based position of the
+// offending record, if the problem lies with a particular one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMapError {
+    pub entry: Option<(usize, String)>,
+    pub reason: String,
+}
+
+impl fmt::Display for IdMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.entry {
+            Some((n, text)) => write!(f, "entry {} ('{}'): {}", n, text, self.reason),
+            None => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+impl IdMap {
+    // Check the records of a map: the ranges they describe must not wrap
+    // around the 32-bit ID space, and must overlap neither inside nor
+    // outside the user namespace.
+    pub fn new(entries: Vec<IdMapEntry>) -> Result<IdMap, IdMapError> {
+        let error = |n: usize, reason: String| IdMapError {
+            entry: Some((n + 1, entries[n].to_string())),
+            reason,
+        };
+
+        if entries.is_empty() {
+            return Err(IdMapError {
+                entry: None,
+                reason: "empty map".to_string(),
+            });
+        }
+        if entries.len() > MAX_ID_MAP_ENTRIES {
+            return Err(error(
+                MAX_ID_MAP_ENTRIES,
+                format!("too many entries (at most {})", MAX_ID_MAP_ENTRIES),
+            ));
+        }
+
+        for (n, e) in entries.iter().enumerate() {
+            if e.length == 0 {
+                return Err(error(n, "length must not be zero".to_string()));
+            }
+            // ID 4294967295 is never valid, as it is (uid_t) -1.
+            for (what, start) in &[("inside", e.inside), ("outside", e.outside)] {
+                if u64::from(*start) + u64::from(e.length) > u64::from(u32::MAX) {
+                    return Err(error(n, format!("{} range exceeds 32-bit IDs", what)));
+                }
+            }
+            for (m, other) in entries.iter().enumerate().take(n) {
+                if ranges_overlap((e.inside, e.length), (other.inside, other.length)) {
+                    return Err(error(n, format!("inside range overlaps entry {}", m + 1)));
+                }
+                if ranges_overlap((e.outside, e.length), (other.outside, other.length)) {
+                    return Err(error(n, format!("outside range overlaps entry {}", m + 1)));
+                }
+            }
+        }
+
+        Ok(IdMap { entries })
+    }
+
+    // A map for a single ID.
+    pub fn single(inside: u32, outside: u32) -> IdMap {
+        IdMap {
+            entries: vec![IdMapEntry {
+                inside,
+                outside,
+                length: 1,
+            }],
+        }
+    }
+
+    pub fn entries(&self) -> &[IdMapEntry] {
+        &self.entries
+    }
+}
+
+// Whether two ranges of IDs, given as start and length, overlap.
+fn ranges_overlap(a: (u32, u32), b: (u32, u32)) -> bool {
+    let end = |(start, length): (u32, u32)| u64::from(start) + u64::from(length);
+    u64::from(a.0) < end(b) && u64::from(b.0) < end(a)
+}
+
+// A map is given as one or more records of the form:
+//
+// ID_inside-ns    ID-outside-ns   length
+//
+// Requiring the user to supply a string that contains newlines is
+// of course inconvenient for command-line use. Thus, we permit the
+// use of commas as well as newlines to delimit records.
+impl FromStr for IdMap {
+    type Err = IdMapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+        for (n, record) in s
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .enumerate()
+        {
+            let error = |reason: String| IdMapError {
+                entry: Some((n + 1, record.to_string())),
+                reason,
+            };
+
+            let fields: Vec<&str> = record.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(error(
+                    "expected 'ID-inside-ns ID-outside-ns length'".to_string(),
+                ));
+            }
+            let mut ids = [0; 3];
+            for (id, field) in ids.iter_mut().zip(&fields) {
+                *id = field
+                    .parse()
+                    .map_err(|_| error(format!("'{}' is not a valid ID or length", field)))?;
+            }
+            entries.push(IdMapEntry {
+                inside: ids[0],
+                outside: ids[1],
+                length: ids[2],
+            });
+        }
+        IdMap::new(entries)
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in &self.entries {
+            writeln!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+// Update the mapping file 'map_file' (a `/proc/PID/uid_map` or
+// `/proc/PID/gid_map` file) with 'map'. The whole map has to be written in
+// a single write(2), and the file may only be written once.
+
+pub fn update_map(map: &IdMap, map_file: &str) -> Result<(), String> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .open(map_file)
+        .map_err(|err| format!("Error opening {}: {}", map_file, err))?;
+    f.write_all(map.to_string().as_bytes())
+        .map_err(|err| format!("Error writing to {}: {}", map_file, err))
+}
+
+// Whether the calling process has CAP_SETGID in its user namespace, which
+// it needs to write a `gid_map` while `setgroups(2)` is allowed.
+
+pub fn has_setgid() -> bool {
+    caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_SETGID).unwrap_or(false)
+}
+
+// Whether system call `setgroups(2)` stays available in a new user
+// namespace. `Auto` denies it only when we lack CAP_SETGID, in which case
+// the kernel would refuse our `gid_map` otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setgroups {
+    Allow,
+    Deny,
+    Auto,
+}
+
+impl Setgroups {
+    pub const VALUES: &'static [&'static str] = &["allow", "deny", "auto"];
+
+    // Decide what `Auto` stands for, based on the capabilities of the
+    // calling process in its own user namespace, which is to be the parent
+    // of the new one.
+    pub fn resolve(self) -> Setgroups {
+        match self {
+            Setgroups::Auto if has_setgid() => Setgroups::Allow,
+            Setgroups::Auto => Setgroups::Deny,
+            policy => policy,
+        }
+    }
+}
+
+impl FromStr for Setgroups {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Setgroups::Allow),
+            "deny" => Ok(Setgroups::Deny),
+            "auto" => Ok(Setgroups::Auto),
+            _ => Err(format!("unsupported setgroups policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Setgroups {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Setgroups::Allow => "allow",
+            Setgroups::Deny => "deny",
+            Setgroups::Auto => "auto",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Write 'policy', which must have been resolved, to 'setgroups_file' (a
+// `/proc/PID/setgroups` file). This must happen before the `gid_map` of
+// that process is written.
+
+pub fn set_setgroups(policy: Setgroups, setgroups_file: &str) -> Result<(), String> {
+    if policy == Setgroups::Auto {
+        return Err(format!("{}: setgroups policy not resolved", setgroups_file));
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .open(setgroups_file)
+        .map_err(|err| format!("Error opening {}: {}", setgroups_file, err))?;
+    f.write_all(format!("{}\n", policy).as_bytes())
+        .map_err(|err| format!("Error writing to {}: {}", setgroups_file, err))
+}
+
+// Disable system call `setgroups(2)` for the process owning 'setgroups_file'
+// (a `/proc/PID/setgroups` file). Since Linux 3.19 this must be done before
+// an unprivileged process may write its `gid_map`.
+
+pub fn disable_setgroups(setgroups_file: &str) -> Result<(), String> {
+    set_setgroups(Setgroups::Deny, setgroups_file)
+}
+
+// A range of subordinate IDs delegated to a user, as listed in
+// `/etc/subuid` and `/etc/subgid`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+// Look up the first range of subordinate IDs delegated to the user with
+// login name 'user' and UID 'uid' in 'path' (`/etc/subuid` or
+// `/etc/subgid`). Records have the form `USER:START:COUNT`, where USER may
+// be a login name or a numeric UID.
+
+pub fn read_subid_range(path: &str, user: &str, uid: u32) -> Result<SubIdRange, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() != 3 {
+            return Err(format!("{}:{}: expected 'USER:START:COUNT'", path, n + 1));
+        }
+        if fields[0] != user && fields[0] != uid.to_string() {
+            continue;
+        }
+        let parse = |field: &str| {
+            field
+                .parse::<u32>()
+                .map_err(|_| format!("{}:{}: invalid number '{}'", path, n + 1, field))
+        };
+        return Ok(SubIdRange {
+            start: parse(fields[1])?,
+            count: parse(fields[2])?,
+        });
+    }
+    Err(format!("{}: no subordinate IDs for user {}", path, user))
+}
+
+// The map used with subordinate IDs: ID 0 inside the user namespace maps to
+// 'id' (the caller's own UID or GID), and IDs 1 to `SUBID_MAP_LENGTH` to the
+// subordinate range.
+
+pub fn subid_map(id: u32, range: SubIdRange) -> Result<IdMap, IdMapError> {
+    IdMap::new(vec![
+        IdMapEntry {
+            inside: 0,
+            outside: id,
+            length: 1,
+        },
+        IdMapEntry {
+            inside: 1,
+            outside: range.start,
+            length: range.count.min(SUBID_MAP_LENGTH),
+        },
+    ])
+}
+
+// Have the set-user-ID 'helper' (`newuidmap` or `newgidmap`) write 'map'
+// for process 'pid'. Unprivileged processes may only map their own ID
+// themselves; the helpers check the map against `/etc/subuid` and
+// `/etc/subgid` instead. `newgidmap` also takes care of
+// `/proc/PID/setgroups`.
+
+pub fn run_newidmap(helper: &str, pid: Pid, map: &IdMap) -> Result<(), String> {
+    let mut cmd = Command::new(helper);
+    cmd.arg(pid.to_string());
+    for e in map.entries() {
+        cmd.args(&[
+            e.inside.to_string(),
+            e.outside.to_string(),
+            e.length.to_string(),
+        ]);
+    }
+
+    let status = cmd
+        .status()
+        .map_err(|err| format!("Error running {}: {}", helper, err))?;
+    if !status.success() {
+        return Err(format!("{} failed: {}", helper, status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fn entry(inside: u32, outside: u32, length: u32) -> IdMapEntry {
+        IdMapEntry {
+            inside,
+            outside,
+            length,
+        }
+    }
+
+    fn reason(map: &str) -> String {
+        map.parse::<IdMap>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_comma_and_newline_separated_records() {
+        let map: IdMap = "0 1000 1, 1 100000 65536\n65537 200000 10,"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            map.entries(),
+            &[
+                entry(0, 1000, 1),
+                entry(1, 100000, 65536),
+                entry(65537, 200000, 10)
+            ]
+        );
+        assert_eq!(
+            map.to_string(),
+            "0 1000 1\n1 100000 65536\n65537 200000 10\n"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        assert_eq!(reason(""), "empty map");
+        assert_eq!(
+            reason("0 1000 1,0 1000"),
+            "entry 2 ('0 1000'): expected 'ID-inside-ns ID-outside-ns length'"
+        );
+        assert_eq!(
+            reason("0 1000 x"),
+            "entry 1 ('0 1000 x'): 'x' is not a valid ID or length"
+        );
+        assert_eq!(
+            reason("-1 1000 1"),
+            "entry 1 ('-1 1000 1'): '-1' is not a valid ID or length"
+        );
+        assert_eq!(
+            reason("0 1000 0"),
+            "entry 1 ('0 1000 0'): length must not be zero"
+        );
+    }
+
+    #[test]
+    fn rejects_ranges_beyond_32_bits() {
+        assert!("4294967294 0 1".parse::<IdMap>().is_ok());
+        assert_eq!(
+            reason("4294967295 0 1"),
+            "entry 1 ('4294967295 0 1'): inside range exceeds 32-bit IDs"
+        );
+        assert_eq!(
+            reason("0 4294967000 1000"),
+            "entry 1 ('0 4294967000 1000'): outside range exceeds 32-bit IDs"
+        );
+        assert_eq!(
+            reason("0 0 4294967296"),
+            "entry 1 ('0 0 4294967296'): '4294967296' is not a valid ID or length"
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_ranges() {
+        assert!("0 1000 10,10 2000 10".parse::<IdMap>().is_ok());
+        assert_eq!(
+            reason("0 1000 10,9 2000 10"),
+            "entry 2 ('9 2000 10'): inside range overlaps entry 1"
+        );
+        assert_eq!(
+            reason("0 1000 10,10 995 10"),
+            "entry 2 ('10 995 10'): outside range overlaps entry 1"
+        );
+    }
+
+    #[test]
+    fn limits_number_of_records() {
+        let records: Vec<String> = (0..=MAX_ID_MAP_ENTRIES as u32)
+            .map(|n| format!("{} {} 1", n, 1000 + n))
+            .collect();
+        assert!(records[..MAX_ID_MAP_ENTRIES]
+            .join(",")
+            .parse::<IdMap>()
+            .is_ok());
+        assert_eq!(
+            reason(&records.join(",")),
+            "entry 341 ('340 1340 1'): too many entries (at most 340)"
+        );
+    }
+
+    fn write_temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    // Write an executable shell script running 'script'. It is written in
+    // one go through a close-on-exec descriptor, and never rewritten, so
+    // that executing it can't fail with ETXTBSY because a process forked
+    // by another test still has it open for writing.
+
+    fn write_temp_helper(name: &str, script: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(&path)
+            .unwrap();
+        f.write_all(format!("#!/bin/sh\n{}\n", script).as_bytes())
+            .unwrap();
+        drop(f);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn reads_subid_range_by_name_or_uid() {
+        let path = write_temp_file(
+            "subuid",
+            "# comment\nalice:100000:65536\n1001:165536:10\nbob:231072:65536\n",
+        );
+        assert_eq!(
+            read_subid_range(&path, "bob", 1002),
+            Ok(SubIdRange {
+                start: 231072,
+                count: 65536
+            })
+        );
+        assert_eq!(
+            read_subid_range(&path, "carol", 1001),
+            Ok(SubIdRange {
+                start: 165536,
+                count: 10
+            })
+        );
+        assert_eq!(
+            read_subid_range(&path, "dave", 1003),
+            Err(format!("{}: no subordinate IDs for user dave", path))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn builds_subid_map() {
+        let range = SubIdRange {
+            start: 100000,
+            count: 1 << 20,
+        };
+        assert_eq!(
+            subid_map(1000, range).unwrap().to_string(),
+            "0 1000 1\n1 100000 65536\n"
+        );
+        let range = SubIdRange {
+            start: 1000,
+            count: 10,
+        };
+        assert_eq!(
+            subid_map(1005, range).unwrap_err().to_string(),
+            "entry 2 ('1 1000 10'): outside range overlaps entry 1"
+        );
+    }
+
+    #[test]
+    fn passes_map_to_helper() {
+        let out = write_temp_file("newuidmap-args", "");
+        let helper = write_temp_helper("newuidmap", &format!("echo \"$@\" > {}", out));
+        let failing = write_temp_helper("newuidmap-failing", "exit 1");
+
+        let map: IdMap = "0 1000 1,1 100000 65536".parse().unwrap();
+        run_newidmap(&helper, Pid::from_raw(42), &map).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "42 0 1000 1 1 100000 65536\n"
+        );
+        assert!(run_newidmap(&failing, Pid::from_raw(42), &map).is_err());
+
+        fs::remove_file(&helper).unwrap();
+        fs::remove_file(&failing).unwrap();
+        fs::remove_file(&out).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+use clap::{Arg, ArgMatches};
+use nix::errno::Errno;
+use nix::unistd::sethostname;
+
+// The `--hostname` and `--domainname` options.
+
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("hostname")
+            .help("host name in the new UTS namespace; implies --uts")
+            .long("hostname")
+            .takes_value(true)
+            .value_name("NAME"),
+        Arg::with_name("domainname")
+            .help("NIS domain name in the new UTS namespace; implies --uts")
+            .long("domainname")
+            .takes_value(true)
+            .value_name("NAME"),
+    ]
+}
+
+// The host name and NIS domain name given with the options of `args()`, if
+// any, as taken by `set_uts_names()`.
+
+pub fn from_matches<'a>(matches: &'a ArgMatches) -> (Option<&'a str>, Option<&'a str>) {
+    (matches.value_of("hostname"), matches.value_of("domainname"))
+}
+
+// Set the NIS domain name of the UTS namespace of the calling process.
+
+pub fn setdomainname(name: &str) -> nix::Result<()> {
+    let res = unsafe { libc::setdomainname(name.as_ptr() as *const libc::c_char, name.len()) };
+    Errno::result(res).map(drop)
+}
+
+// Set the host name and NIS domain name, if given, of the UTS namespace of
+// the calling process. This has to be called from inside the new UTS
+// namespace, after the ID maps of a new user namespace have been written,
+// since it needs CAP_SYS_ADMIN in the user namespace owning it.
+
+pub fn set_uts_names(hostname: Option<&str>, domainname: Option<&str>) -> nix::Result<()> {
+    if let Some(name) = hostname {
+        sethostname(name)?;
+    }
+    if let Some(name) = domainname {
+        setdomainname(name)?;
+    }
+    Ok(())
+}